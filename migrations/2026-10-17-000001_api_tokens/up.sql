@@ -0,0 +1,11 @@
+CREATE TABLE trekkie_api_tokens (
+    id UUID PRIMARY KEY,
+    owner UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_used TIMESTAMP,
+    revoked BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE INDEX trekkie_api_tokens_owner ON trekkie_api_tokens (owner);
@@ -0,0 +1 @@
+DROP TABLE trekkie_v1_normalized_runs;
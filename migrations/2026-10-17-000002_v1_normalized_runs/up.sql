@@ -0,0 +1,7 @@
+-- v1 runs whose start and end time are stored in true utc, either because they were submitted
+-- after the timezone fix or because they were re-normalized by --renormalize-v1-runs
+CREATE TABLE trekkie_v1_normalized_runs (
+    trekkie_run UUID PRIMARY KEY REFERENCES trekkie_runs(id) ON DELETE CASCADE,
+    shifted_by_minutes INT NOT NULL,
+    normalized_at TIMESTAMP NOT NULL DEFAULT now()
+);
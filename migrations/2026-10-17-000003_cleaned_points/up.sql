@@ -0,0 +1,11 @@
+-- track of a run after removing outliers and smoothing, derived from gps_points whenever the run
+-- is finished or cleaned again on demand
+CREATE TABLE trekkie_cleaned_points (
+    trekkie_run UUID NOT NULL REFERENCES trekkie_runs(id) ON DELETE CASCADE,
+    timestamp TIMESTAMP NOT NULL,
+    lat DOUBLE PRECISION NOT NULL,
+    lon DOUBLE PRECISION NOT NULL,
+    elevation DOUBLE PRECISION,
+    raw_point BIGINT NOT NULL REFERENCES gps_points(id) ON DELETE CASCADE,
+    PRIMARY KEY (trekkie_run, timestamp)
+);
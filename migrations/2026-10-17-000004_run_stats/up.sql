@@ -0,0 +1,21 @@
+-- statistics of a run, computed when the run is finished. Distances are in meters, times in
+-- seconds and speeds in meters per second, the intervals columns count the sampling intervals up
+-- to the given length.
+CREATE TABLE trekkie_run_stats (
+    trekkie_run UUID PRIMARY KEY REFERENCES trekkie_runs(id) ON DELETE CASCADE,
+    points BIGINT NOT NULL,
+    distance DOUBLE PRECISION NOT NULL,
+    duration DOUBLE PRECISION NOT NULL,
+    moving_time DOUBLE PRECISION NOT NULL,
+    average_speed DOUBLE PRECISION,
+    max_speed DOUBLE PRECISION,
+    longest_gap DOUBLE PRECISION,
+    mean_accuracy DOUBLE PRECISION,
+    intervals_1s BIGINT NOT NULL,
+    intervals_2s BIGINT NOT NULL,
+    intervals_5s BIGINT NOT NULL,
+    intervals_10s BIGINT NOT NULL,
+    intervals_30s BIGINT NOT NULL,
+    intervals_longer BIGINT NOT NULL,
+    computed_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,20 @@
+-- location candidates of reporting points, one per r09 telegram which the vehicle of a run sent
+-- while the run was recorded, at the position interpolated from the track of the run
+CREATE TABLE trekkie_correlations (
+    trekkie_run UUID NOT NULL REFERENCES trekkie_runs(id) ON DELETE CASCADE,
+    telegram BIGINT NOT NULL,
+    time TIMESTAMP NOT NULL,
+    region BIGINT NOT NULL,
+    reporting_point INT NOT NULL,
+    junction INT NOT NULL,
+    request_status SMALLINT NOT NULL,
+    lat DOUBLE PRECISION NOT NULL,
+    lon DOUBLE PRECISION NOT NULL,
+    fix_delta DOUBLE PRECISION NOT NULL,
+    confidence DOUBLE PRECISION NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    accuracy DOUBLE PRECISION,
+    PRIMARY KEY (trekkie_run, telegram)
+);
+
+CREATE INDEX trekkie_correlations_reporting_point ON trekkie_correlations (region, reporting_point);
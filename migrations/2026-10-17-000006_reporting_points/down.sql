@@ -0,0 +1 @@
+DROP TABLE trekkie_reporting_points;
@@ -0,0 +1,14 @@
+-- position of every reporting point aggregated from the correlations of all runs, rebuilt
+-- periodically by the aggregation job
+CREATE TABLE trekkie_reporting_points (
+    region BIGINT NOT NULL,
+    reporting_point INT NOT NULL,
+    lat DOUBLE PRECISION NOT NULL,
+    lon DOUBLE PRECISION NOT NULL,
+    samples INT NOT NULL,
+    outliers INT NOT NULL,
+    runs INT NOT NULL,
+    spread DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (region, reporting_point)
+);
@@ -0,0 +1,7 @@
+-- when a run was created and when it last received points, used by the reaper to finish or
+-- delete live runs the app stopped sending to
+CREATE TABLE trekkie_run_activity (
+    trekkie_run UUID PRIMARY KEY REFERENCES trekkie_runs(id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_point_at TIMESTAMP
+);
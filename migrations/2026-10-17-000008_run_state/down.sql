@@ -0,0 +1 @@
+ALTER TABLE trekkie_run_activity DROP COLUMN state, DROP COLUMN aborted_reason;
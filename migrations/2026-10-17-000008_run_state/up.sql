@@ -0,0 +1,11 @@
+-- state of a run: created, recording, finished, aborted or empty. Aborted runs keep the reason
+-- the app gave when cancelling them.
+ALTER TABLE trekkie_run_activity
+    ADD COLUMN state TEXT NOT NULL DEFAULT 'created',
+    ADD COLUMN aborted_reason TEXT;
+
+UPDATE trekkie_run_activity SET state = 'recording' WHERE last_point_at IS NOT NULL;
+
+UPDATE trekkie_run_activity SET state = 'finished'
+    FROM trekkie_runs
+    WHERE trekkie_runs.id = trekkie_run_activity.trekkie_run AND trekkie_runs.finished;
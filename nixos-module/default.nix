@@ -30,6 +30,20 @@ in
         File from which the password salt can be taken
       '';
     };
+    sessionKeyFile = mkOption {
+      type = types.nullOr (types.either types.path types.string);
+      default = null;
+      description = ''
+        File with at least 64 random bytes the session cookies are encrypted with
+      '';
+    };
+    previousSessionKeyFile = mkOption {
+      type = types.nullOr (types.either types.path types.string);
+      default = null;
+      description = ''
+        Session key file before the last rotation, accepted during the grace period
+      '';
+    };
     database = {
       host = mkOption {
         type = types.str;
@@ -147,6 +161,10 @@ in
             "TREKKIE_REDIS_PORT" = "${toString cfg.redis.port}";
             "TREKKIE_REDIS_HOST" = "${cfg.redis.host}";
             "CHEMO_GRPC" = "http://${cfg.grpc.host}:${toString cfg.grpc.port}";
+          } // lib.optionalAttrs (cfg.sessionKeyFile != null) {
+            "TREKKIE_SESSION_KEY_PATH" = "${cfg.sessionKeyFile}";
+          } // lib.optionalAttrs (cfg.previousSessionKeyFile != null) {
+            "TREKKIE_PREVIOUS_SESSION_KEY_PATH" = "${cfg.previousSessionKeyFile}";
           };
 
           serviceConfig = {
@@ -0,0 +1,413 @@
+use crate::models::ReportingPoint;
+use crate::processing::{distance, DEFAULT_ACCURACY, MIN_ACCURACY};
+use crate::routes::ServerError;
+use crate::DbPool;
+
+use actix_web::web;
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::{count_star, max};
+use diesel::upsert::excluded;
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::{error, info};
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// candidates further away from the median than this multiple of the median distance are
+/// rejected as outliers
+const OUTLIER_FACTOR: f64 = 3.0;
+
+/// candidates within this many meters of the median are never rejected, otherwise a few close
+/// candidates would reject everything else
+const MIN_OUTLIER_DISTANCE: f64 = 15.0;
+
+/// maximum amount of rows per insert statement
+const INSERT_CHUNK_SIZE: usize = 5000;
+
+/// candidates stored up to this many seconds before a position was updated may not have been
+/// committed yet, so their reporting point is estimated once more
+const COMMIT_MARGIN: i64 = 60;
+
+/// location candidate of a reporting point from one telegram
+struct Candidate {
+    trekkie_run: Uuid,
+    lat: f64,
+    lon: f64,
+    weight: f64,
+}
+
+/// value at which the candidates with smaller values carry half of the total weight
+fn weighted_median(mut values: Vec<(f64, f64)>) -> f64 {
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let half = values.iter().map(|(_, weight)| weight).sum::<f64>() / 2.0;
+
+    let mut cumulative = 0.0;
+    for (value, weight) in &values {
+        cumulative += weight;
+        if cumulative >= half {
+            return *value;
+        }
+    }
+
+    values.last().map_or(0.0, |(value, _)| *value)
+}
+
+/// Estimates the position of one reporting point. Candidates far from the weighted median are
+/// rejected, the position is the weighted mean of the remaining ones.
+fn estimate(region: i64, reporting_point: i32, candidates: &[Candidate]) -> ReportingPoint {
+    let median_lat = weighted_median(candidates.iter().map(|c| (c.lat, c.weight)).collect());
+    let median_lon = weighted_median(candidates.iter().map(|c| (c.lon, c.weight)).collect());
+
+    let distances: Vec<f64> = candidates
+        .iter()
+        .map(|candidate| distance(median_lat, median_lon, candidate.lat, candidate.lon))
+        .collect();
+    let mut sorted = distances.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let limit = (sorted[sorted.len() / 2] * OUTLIER_FACTOR).max(MIN_OUTLIER_DISTANCE);
+
+    let inliers: Vec<&Candidate> = candidates
+        .iter()
+        .zip(&distances)
+        .filter(|(_, distance)| **distance <= limit)
+        .map(|(candidate, _)| candidate)
+        .collect();
+
+    let total_weight: f64 = inliers.iter().map(|c| c.weight).sum();
+    let lat = inliers.iter().map(|c| c.lat * c.weight).sum::<f64>() / total_weight;
+    let lon = inliers.iter().map(|c| c.lon * c.weight).sum::<f64>() / total_weight;
+    let spread = (inliers
+        .iter()
+        .map(|c| c.weight * distance(lat, lon, c.lat, c.lon).powi(2))
+        .sum::<f64>()
+        / total_weight)
+        .sqrt();
+    let runs: HashSet<Uuid> = inliers.iter().map(|c| c.trekkie_run).collect();
+
+    ReportingPoint {
+        region,
+        reporting_point,
+        lat,
+        lon,
+        samples: inliers.len() as i32,
+        outliers: (candidates.len() - inliers.len()) as i32,
+        runs: runs.len() as i32,
+        spread,
+        updated_at: Utc::now().naive_utc(),
+    }
+}
+
+/// loads the location candidates of one reporting point, every candidate is weighted by its
+/// confidence and the inverse square of the accuracy of its gps fix
+fn load_candidates(
+    region: i64,
+    reporting_point: i32,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<Candidate>, ServerError> {
+    use crate::schema::trekkie_correlations::dsl as correlations;
+
+    let rows = correlations::trekkie_correlations
+        .filter(correlations::region.eq(region))
+        .filter(correlations::reporting_point.eq(reporting_point))
+        .select((
+            correlations::trekkie_run,
+            correlations::lat,
+            correlations::lon,
+            correlations::confidence,
+            correlations::accuracy,
+        ))
+        .load::<(Uuid, f64, f64, f64, Option<f64>)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading correlations {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(trekkie_run, lat, lon, confidence, accuracy)| {
+            let accuracy = accuracy
+                .filter(|accuracy| accuracy.is_finite() && *accuracy > 0.0)
+                .unwrap_or(DEFAULT_ACCURACY)
+                .max(MIN_ACCURACY);
+            let weight = confidence / (accuracy * accuracy);
+
+            (weight.is_finite() && weight > 0.0).then_some(Candidate {
+                trekkie_run,
+                lat,
+                lon,
+                weight,
+            })
+        })
+        .collect())
+}
+
+/// Brings the position of every region and reporting point up to date with the location
+/// candidates of all correlated runs. Only reporting points whose candidates changed since their
+/// position was stored are estimated again, one at a time, and positions without candidates are
+/// removed. Returns the amount of updated positions.
+pub fn aggregate_reporting_points(
+    database_connection: &mut PgConnection,
+) -> Result<usize, ServerError> {
+    use crate::schema::trekkie_correlations::dsl as correlations;
+    use crate::schema::trekkie_reporting_points::dsl as points;
+
+    // amount of candidates and time of the newest one per reporting point
+    let summaries = correlations::trekkie_correlations
+        .group_by((correlations::region, correlations::reporting_point))
+        .select((
+            correlations::region,
+            correlations::reporting_point,
+            count_star(),
+            max(correlations::created_at),
+        ))
+        .load::<(i64, i32, i64, Option<NaiveDateTime>)>(database_connection)
+        .map_err(|e| {
+            error!("database error while summarizing correlations {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    let stored: HashMap<(i64, i32), (i64, NaiveDateTime)> = points::trekkie_reporting_points
+        .select((
+            points::region,
+            points::reporting_point,
+            points::samples,
+            points::outliers,
+            points::updated_at,
+        ))
+        .load::<(i64, i32, i32, i32, NaiveDateTime)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading reporting points {:?}", e);
+            ServerError::InternalError
+        })?
+        .into_iter()
+        .map(|(region, reporting_point, samples, outliers, updated_at)| {
+            (
+                (region, reporting_point),
+                (i64::from(samples) + i64::from(outliers), updated_at),
+            )
+        })
+        .collect();
+
+    // removed candidates change the amount, replaced or added ones the time of the newest one
+    let margin = chrono::Duration::seconds(COMMIT_MARGIN);
+    let changed: Vec<(i64, i32)> = summaries
+        .iter()
+        .filter(|(region, reporting_point, count, newest)| {
+            match stored.get(&(*region, *reporting_point)) {
+                Some((candidates, updated_at)) => {
+                    candidates != count
+                        || newest.is_some_and(|newest| newest + margin >= *updated_at)
+                }
+                None => true,
+            }
+        })
+        .map(|(region, reporting_point, _, _)| (*region, *reporting_point))
+        .collect();
+
+    let present: HashSet<(i64, i32)> = summaries
+        .iter()
+        .map(|(region, reporting_point, _, _)| (*region, *reporting_point))
+        .collect();
+    let mut removed: Vec<(i64, i32)> = stored
+        .keys()
+        .filter(|key| !present.contains(key))
+        .copied()
+        .collect();
+
+    let mut estimates: Vec<ReportingPoint> = Vec::with_capacity(changed.len());
+    for (region, reporting_point) in changed {
+        let candidates = load_candidates(region, reporting_point, database_connection)?;
+
+        if candidates.is_empty() {
+            removed.push((region, reporting_point));
+        } else {
+            estimates.push(estimate(region, reporting_point, &candidates));
+        }
+    }
+
+    database_connection
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            for (region, reporting_point) in &removed {
+                diesel::delete(
+                    points::trekkie_reporting_points
+                        .filter(points::region.eq(region))
+                        .filter(points::reporting_point.eq(reporting_point)),
+                )
+                .execute(conn)?;
+            }
+
+            for chunk in estimates.chunks(INSERT_CHUNK_SIZE) {
+                diesel::insert_into(points::trekkie_reporting_points)
+                    .values(chunk)
+                    .on_conflict((points::region, points::reporting_point))
+                    .do_update()
+                    .set((
+                        points::lat.eq(excluded(points::lat)),
+                        points::lon.eq(excluded(points::lon)),
+                        points::samples.eq(excluded(points::samples)),
+                        points::outliers.eq(excluded(points::outliers)),
+                        points::runs.eq(excluded(points::runs)),
+                        points::spread.eq(excluded(points::spread)),
+                        points::updated_at.eq(excluded(points::updated_at)),
+                    ))
+                    .execute(conn)?;
+            }
+
+            Ok(())
+        })
+        .map_err(|e| {
+            error!("while trying to store reporting points {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    Ok(estimates.len())
+}
+
+/// spawns the background task which aggregates the reporting points in the given interval
+pub fn start(pool: web::Data<DbPool>, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let pool = pool.clone();
+            let result = web::block(move || match pool.get() {
+                Ok(mut database_connection) => aggregate_reporting_points(&mut database_connection),
+                Err(e) => {
+                    error!("cannot get connection from connection pool {:?}", e);
+                    Err(ServerError::InternalError)
+                }
+            })
+            .await;
+
+            match result {
+                Ok(Ok(count)) => info!("updated the positions of {} reporting points", count),
+                Ok(Err(_)) => error!("cannot aggregate reporting points"),
+                Err(e) => error!("reporting point aggregation panicked {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::tests::assert_close;
+
+    /// meters per degree of latitude
+    const METERS_PER_DEGREE: f64 = 6_371_000.0 * std::f64::consts::PI / 180.0;
+
+    /// candidate north and east in meters of 51° N 13° E
+    fn candidate(run: u128, north: f64, east: f64, weight: f64) -> Candidate {
+        Candidate {
+            trekkie_run: Uuid::from_u128(run),
+            lat: 51.0 + north / METERS_PER_DEGREE,
+            lon: 13.0 + east / (METERS_PER_DEGREE * 51.0f64.to_radians().cos()),
+            weight,
+        }
+    }
+
+    #[test]
+    fn median() {
+        assert_eq!(weighted_median(Vec::new()), 0.0);
+        assert_eq!(weighted_median(vec![(4.0, 1.0)]), 4.0);
+        assert_eq!(
+            weighted_median(vec![(3.0, 1.0), (1.0, 1.0), (2.0, 1.0)]),
+            2.0
+        );
+        // with an even split the lower value is taken
+        assert_eq!(weighted_median(vec![(3.0, 1.0), (1.0, 1.0)]), 1.0);
+        // a heavy candidate outweighs several light ones
+        assert_eq!(
+            weighted_median(vec![(1.0, 1.0), (2.0, 1.0), (10.0, 5.0)]),
+            10.0
+        );
+        assert_eq!(
+            weighted_median(vec![(2.0, 1.0), (2.0, 1.0), (5.0, 1.0)]),
+            2.0
+        );
+    }
+
+    #[test]
+    fn single_candidate() {
+        let position = estimate(1, 42, &[candidate(1, 10.0, 20.0, 0.5)]);
+        let expected = candidate(1, 10.0, 20.0, 0.5);
+
+        assert_eq!((position.region, position.reporting_point), (1, 42));
+        assert_close(position.lat, expected.lat);
+        assert_close(position.lon, expected.lon);
+        assert_eq!(
+            (position.samples, position.outliers, position.runs),
+            (1, 0, 1)
+        );
+        assert_close(position.spread, 0.0);
+    }
+
+    #[test]
+    fn identical_candidates() {
+        let candidates: Vec<Candidate> = (0..4).map(|run| candidate(run, 0.0, 0.0, 1.0)).collect();
+        let position = estimate(1, 42, &candidates);
+
+        assert_close(position.lat, 51.0);
+        assert_close(position.lon, 13.0);
+        assert_eq!(
+            (position.samples, position.outliers, position.runs),
+            (4, 0, 4)
+        );
+        assert_close(position.spread, 0.0);
+    }
+
+    #[test]
+    fn weighted_mean() {
+        // three times the weight pulls the position to a quarter of the distance
+        let position = estimate(
+            1,
+            42,
+            &[candidate(1, 0.0, 0.0, 3.0), candidate(2, 8.0, 0.0, 1.0)],
+        );
+
+        assert!((distance(position.lat, position.lon, 51.0, 13.0) - 2.0).abs() < 1e-3);
+        assert_close(position.lon, 13.0);
+        assert!((position.spread - 12.0f64.sqrt()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_outliers() {
+        let candidates = vec![
+            candidate(1, 0.0, 0.0, 1.0),
+            candidate(1, 4.0, 0.0, 1.0),
+            candidate(2, -4.0, 0.0, 1.0),
+            candidate(2, 0.0, 4.0, 1.0),
+            candidate(3, 0.0, -4.0, 1.0),
+            // a telegram matched with a wrong track
+            candidate(4, 500.0, 0.0, 1.0),
+        ];
+        let position = estimate(1, 42, &candidates);
+
+        assert!(distance(position.lat, position.lon, 51.0, 13.0) < 1e-3);
+        assert_eq!(
+            (position.samples, position.outliers, position.runs),
+            (5, 1, 3)
+        );
+    }
+
+    #[test]
+    fn keeps_close_candidates() {
+        // the median distance is zero, but candidates within 15 m are never rejected
+        let candidates = vec![
+            candidate(1, 0.0, 0.0, 1.0),
+            candidate(2, 0.0, 0.0, 1.0),
+            candidate(3, 0.0, 0.0, 1.0),
+            candidate(4, 14.0, 0.0, 1.0),
+            candidate(5, 16.0, 0.0, 1.0),
+        ];
+        let position = estimate(1, 42, &candidates);
+
+        assert_eq!(
+            (position.samples, position.outliers, position.runs),
+            (4, 1, 4)
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use tlms::grpc::chemo_client::ChemoClient;
+use tlms::grpc::GrpcGpsPoint;
+use tlms::trekkie::TrekkieRun;
+
+use chrono::NaiveDateTime;
+use futures::future::{self, Either};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tonic::transport::Channel;
+use tonic::Code;
+use utoipa::ToSchema;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// first delay after chemo could not be reached
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// upper bound for the delay between two connection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// how often a point is sent before it is dropped, reconnecting does not count as attempt
+const MAX_SEND_ATTEMPTS: u32 = 5;
+
+/// Queue lengths and dropped points of the chemo forwarder
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct ChemoStatus {
+    /// whether a chemo grpc host is configured
+    pub enabled: bool,
+    /// live points waiting to be sent
+    pub queued_live: usize,
+    /// replayed points waiting to be sent
+    pub queued_replay: usize,
+    /// points dropped since the start because a queue was full or chemo rejected them
+    pub dropped: u64,
+}
+
+/// Handle for forwarding live gps points to chemo. Points are put into a bounded queue which is
+/// drained by a single background task that keeps one grpc connection open and reconnects with
+/// exponential backoff, so points survive short chemo outages. If the queue is full points are
+/// dropped and counted. Replayed tracks go through a separate queue which is only drained while
+/// no live point is waiting, so long replays never push live points out.
+#[derive(Clone)]
+pub struct ChemoForwarder {
+    sender: Option<mpsc::Sender<GrpcGpsPoint>>,
+    replay_sender: Option<mpsc::Sender<GrpcGpsPoint>>,
+    dropped: Arc<AtomicU64>,
+    forward_uploads: bool,
+}
+
+/// builds the grpc message for a gps point of the given run
+pub fn grpc_point(
+    trekkie_run: &TrekkieRun,
+    time: NaiveDateTime,
+    lat: f64,
+    lon: f64,
+) -> GrpcGpsPoint {
+    GrpcGpsPoint {
+        time: time.and_utc().timestamp_millis() as u64,
+        id: 0,
+        region: trekkie_run.region,
+        lat,
+        lon,
+        line: trekkie_run.line,
+        run: trekkie_run.run,
+    }
+}
+
+impl ChemoForwarder {
+    /// spawns the background task which sends the queued points to the given chemo host, if no
+    /// host is configured points are not forwarded at all
+    pub fn start(
+        grpc_host: Option<String>,
+        queue_size: usize,
+        forward_uploads: bool,
+    ) -> ChemoForwarder {
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let (sender, replay_sender) = match grpc_host {
+            Some(host) => {
+                let (sender, receiver) = mpsc::channel(queue_size.max(1));
+                let (replay_sender, replay_receiver) = mpsc::channel(queue_size.max(1));
+                actix_web::rt::spawn(forward(host, receiver, replay_receiver, dropped.clone()));
+                (Some(sender), Some(replay_sender))
+            }
+            None => {
+                warn!("no chemo grpc host configured, live gps points are not forwarded");
+                (None, None)
+            }
+        };
+
+        ChemoForwarder {
+            sender,
+            replay_sender,
+            dropped,
+            forward_uploads,
+        }
+    }
+
+    /// if uploaded tracks are forwarded to chemo by default
+    pub fn forward_uploads(&self) -> bool {
+        self.forward_uploads
+    }
+
+    /// current queue lengths and the amount of dropped points
+    pub fn status(&self) -> ChemoStatus {
+        let queued = |sender: &Option<mpsc::Sender<GrpcGpsPoint>>| {
+            sender
+                .as_ref()
+                .map_or(0, |sender| sender.max_capacity() - sender.capacity())
+        };
+
+        ChemoStatus {
+            enabled: self.sender.is_some(),
+            queued_live: queued(&self.sender),
+            queued_replay: queued(&self.replay_sender),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Replays a recorded track to chemo in a background task. The points are sorted by their
+    /// timestamp and the task waits for free space in the replay queue instead of dropping
+    /// points, so even long tracks arrive completely. Returns false if forwarding is disabled.
+    pub fn replay(&self, mut points: Vec<GrpcGpsPoint>) -> bool {
+        let sender = match &self.replay_sender {
+            Some(sender) => sender.clone(),
+            None => return false,
+        };
+
+        points.sort_by_key(|point| point.time);
+
+        actix_web::rt::spawn(async move {
+            let total = points.len();
+            for point in points {
+                if sender.send(point).await.is_err() {
+                    error!("chemo forwarder is not running, aborting replay");
+                    return;
+                }
+            }
+            info!("queued {} replayed gps points for chemo", total);
+        });
+
+        true
+    }
+
+    /// queues the points for sending them to chemo
+    pub fn enqueue(&self, points: Vec<GrpcGpsPoint>) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+
+        for point in points {
+            if let Err(e) = sender.try_send(point) {
+                let total = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                match e {
+                    mpsc::error::TrySendError::Full(_) => {
+                        warn!("chemo queue is full, dropping gps point ({total} dropped so far)")
+                    }
+                    mpsc::error::TrySendError::Closed(_) => {
+                        error!("chemo forwarder is not running, dropping gps point ({total} dropped so far)")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// errors after which the same point is tried again after reconnecting, everything else means
+/// chemo will never accept the point
+fn is_transient(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded)
+}
+
+/// waits for the next point to send, waiting live points always go before replayed ones
+async fn next_point(
+    live: &mut mpsc::Receiver<GrpcGpsPoint>,
+    replay: &mut mpsc::Receiver<GrpcGpsPoint>,
+) -> Option<GrpcGpsPoint> {
+    // select polls the live queue first, receiving is cancel safe so the losing side keeps its
+    // point
+    let next = {
+        let live_point = live.recv();
+        let replayed_point = replay.recv();
+        futures::pin_mut!(live_point, replayed_point);
+        match future::select(live_point, replayed_point).await {
+            Either::Left((point, _)) => Either::Left(point),
+            Either::Right((point, _)) => Either::Right(point),
+        }
+    };
+
+    match next {
+        Either::Left(None) => replay.recv().await,
+        Either::Right(None) => live.recv().await,
+        Either::Left(point) | Either::Right(point) => point,
+    }
+}
+
+/// drains the queues and delivers every point to chemo
+async fn forward(
+    grpc_host: String,
+    mut receiver: mpsc::Receiver<GrpcGpsPoint>,
+    mut replay_receiver: mpsc::Receiver<GrpcGpsPoint>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut client: Option<ChemoClient<Channel>> = None;
+    let mut backoff = INITIAL_BACKOFF;
+
+    while let Some(point) = next_point(&mut receiver, &mut replay_receiver).await {
+        let mut attempts = 0;
+        loop {
+            if client.is_none() {
+                match ChemoClient::connect(grpc_host.clone()).await {
+                    Ok(connected) => {
+                        info!("connected to chemo at {}", grpc_host);
+                        client = Some(connected);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Cannot connect to GRPC Host: {} with error {:?}, retrying in {:?}",
+                            grpc_host, &e, backoff
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        continue;
+                    }
+                }
+            }
+
+            let connected = match client.as_mut() {
+                Some(connected) => connected,
+                None => continue,
+            };
+
+            attempts += 1;
+            match connected
+                .receive_gps(tonic::Request::new(point.clone()))
+                .await
+            {
+                Ok(_) => {
+                    backoff = INITIAL_BACKOFF;
+                    break;
+                }
+                Err(e) if is_transient(e.code()) && attempts < MAX_SEND_ATTEMPTS => {
+                    warn!(
+                        "Error while sending gps point: {:?}, retrying in {:?}",
+                        e, backoff
+                    );
+                    client = None;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => {
+                    let total = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    error!(
+                        "chemo rejected gps point after {} attempts: {:?} ({total} dropped so far)",
+                        attempts, e
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::structs::Args;
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{PgConnection, QueryDsl, RunQueryDsl};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+
+/// kilometers per degree of latitude
+const KM_PER_DEGREE: f64 = 111.32;
+
+/// Position of a polygon corner
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Coordinate {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Area gps points of a region are expected in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Geofence {
+    Bbox {
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    },
+    /// closed polygon, the last corner is connected to the first one
+    Polygon(Vec<Coordinate>),
+}
+
+impl Geofence {
+    /// square box reaching radius kilometers from the center in every direction
+    pub fn around(lat: f64, lon: f64, radius: f64) -> Geofence {
+        let lat_offset = radius / KM_PER_DEGREE;
+        let lon_offset = radius / (KM_PER_DEGREE * lat.to_radians().cos().max(0.01));
+
+        Geofence::Bbox {
+            min_lat: lat - lat_offset,
+            min_lon: lon - lon_offset,
+            max_lat: lat + lat_offset,
+            max_lon: lon + lon_offset,
+        }
+    }
+
+    /// checks if the position lies inside of the geofence
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match self {
+            Geofence::Bbox {
+                min_lat,
+                min_lon,
+                max_lat,
+                max_lon,
+            } => (*min_lat..=*max_lat).contains(&lat) && (*min_lon..=*max_lon).contains(&lon),
+            Geofence::Polygon(corners) => {
+                // ray casting, counts the edges crossed by a ray from the position to the east
+                let mut inside = false;
+                for (index, corner) in corners.iter().enumerate() {
+                    let previous = corners[(index + corners.len() - 1) % corners.len()];
+                    if (corner.lat > lat) != (previous.lat > lat)
+                        && lon
+                            < (previous.lon - corner.lon) * (lat - corner.lat)
+                                / (previous.lat - corner.lat)
+                                + corner.lon
+                    {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Settings of a single region from the region config file
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RegionConfig {
+    /// timezone the clients in this region record their local times in
+    pub timezone: Option<Tz>,
+    /// area of the region, derived from the center in the tlms regions table if missing
+    pub geofence: Option<Geofence>,
+}
+
+/// Region specific settings, loaded from the json file given with `--region-config` which maps
+/// region ids to [`RegionConfig`]
+#[derive(Debug)]
+pub struct Regions {
+    regions: HashMap<i64, RegionConfig>,
+    /// geofences of the regions without configured geofence
+    derived_geofences: HashMap<i64, Geofence>,
+    default_timezone: Tz,
+}
+
+impl Regions {
+    pub fn load(args: &Args, database_connection: &mut PgConnection) -> Regions {
+        let default_timezone: Tz = args
+            .default_timezone
+            .parse()
+            .expect("default timezone is not a valid IANA timezone!");
+
+        let regions: HashMap<i64, RegionConfig> = match &args.region_config {
+            Some(path) => {
+                let content = fs::read_to_string(path).expect("cannot read region config file!");
+                serde_json::from_str(&content).expect("cannot parse region config file!")
+            }
+            None => HashMap::new(),
+        };
+        for (region, config) in &regions {
+            if let Some(Geofence::Polygon(corners)) = &config.geofence {
+                assert!(
+                    corners.len() >= 3,
+                    "geofence of region {} needs at least three corners!",
+                    region
+                );
+            }
+        }
+        info!("loaded configuration of {} regions", regions.len());
+
+        let derived_geofences = match derived_geofences(args.region_radius, database_connection) {
+            Ok(geofences) => geofences,
+            Err(e) => {
+                warn!(
+                    "cannot load region centers, only configured geofences are used {:?}",
+                    e
+                );
+                HashMap::new()
+            }
+        };
+
+        Regions {
+            regions,
+            derived_geofences,
+            default_timezone,
+        }
+    }
+
+    /// configuration of the given region if there is any
+    pub fn get(&self, region: i64) -> Option<&RegionConfig> {
+        self.regions.get(&region)
+    }
+
+    /// timezone of the region, falls back to the default timezone
+    pub fn timezone(&self, region: i64) -> Tz {
+        self.get(region)
+            .and_then(|config| config.timezone)
+            .unwrap_or(self.default_timezone)
+    }
+
+    /// geofence of the region, the configured one takes precedence over the derived one
+    pub fn geofence(&self, region: i64) -> Option<&Geofence> {
+        self.get(region)
+            .and_then(|config| config.geofence.as_ref())
+            .or_else(|| self.derived_geofences.get(&region))
+    }
+}
+
+/// builds a geofence around the center of every region in the tlms regions table
+fn derived_geofences(
+    radius: f64,
+    database_connection: &mut PgConnection,
+) -> Result<HashMap<i64, Geofence>, diesel::result::Error> {
+    use tlms::schema::regions::dsl::{id, lat, lon, regions};
+
+    Ok(regions
+        .select((id, lat, lon))
+        .load::<(i64, f64, f64)>(database_connection)?
+        .into_iter()
+        .map(|(region, center_lat, center_lon)| {
+            (region, Geofence::around(center_lat, center_lon, radius))
+        })
+        .collect())
+}
+
+/// Interprets a wall clock time in the given timezone and returns the matching utc time. During
+/// the switch to winter time the earlier of both possible times is used, times which are skipped
+/// by the switch to summer time don't exist and return None.
+pub fn local_to_utc<T: TimeZone>(timezone: &T, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+/// fixed offset from a utc offset in minutes
+pub fn offset_from_minutes(minutes: i32) -> Option<FixedOffset> {
+    FixedOffset::east_opt(minutes.checked_mul(60)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn polygon(corners: &[(f64, f64)]) -> Geofence {
+        Geofence::Polygon(
+            corners
+                .iter()
+                .map(|(lat, lon)| Coordinate {
+                    lat: *lat,
+                    lon: *lon,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn bbox() {
+        let geofence = Geofence::Bbox {
+            min_lat: 51.0,
+            min_lon: 13.6,
+            max_lat: 51.2,
+            max_lon: 13.9,
+        };
+
+        assert!(geofence.contains(51.05, 13.74));
+        // edges belong to the box
+        assert!(geofence.contains(51.0, 13.6));
+        assert!(geofence.contains(51.2, 13.9));
+        assert!(!geofence.contains(50.99, 13.74));
+        assert!(!geofence.contains(51.05, 13.91));
+    }
+
+    #[test]
+    fn around() {
+        let geofence = Geofence::around(51.0, 13.0, 10.0);
+        let lon_per_km = 1.0 / (KM_PER_DEGREE * 51.0f64.to_radians().cos());
+
+        assert!(geofence.contains(51.0, 13.0));
+        assert!(geofence.contains(51.0 + 9.9 / KM_PER_DEGREE, 13.0));
+        assert!(!geofence.contains(51.0 + 10.1 / KM_PER_DEGREE, 13.0));
+        assert!(geofence.contains(51.0, 13.0 - 9.9 * lon_per_km));
+        assert!(!geofence.contains(51.0, 13.0 - 10.1 * lon_per_km));
+    }
+
+    #[test]
+    fn concave_polygon() {
+        // L shape, the north east quarter is cut out
+        let geofence = polygon(&[
+            (0.0, 0.0),
+            (0.0, 2.0),
+            (1.0, 2.0),
+            (1.0, 1.0),
+            (2.0, 1.0),
+            (2.0, 0.0),
+        ]);
+
+        assert!(geofence.contains(0.5, 1.5));
+        assert!(geofence.contains(1.5, 0.5));
+        assert!(!geofence.contains(1.5, 1.5));
+        assert!(!geofence.contains(-0.5, 0.5));
+        assert!(!geofence.contains(3.0, 3.0));
+        // the ray to the east passes exactly through a corner
+        assert!(geofence.contains(1.0, 0.5));
+    }
+
+    #[test]
+    fn degenerate_polygons() {
+        assert!(!polygon(&[]).contains(0.0, 0.0));
+        assert!(!polygon(&[(0.0, 0.0)]).contains(0.0, 0.0));
+        assert!(!polygon(&[(0.0, 0.0), (2.0, 2.0)]).contains(1.0, 1.0));
+
+        // corners on a line enclose no area
+        let line = polygon(&[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)]);
+        assert!(!line.contains(0.5, 0.5));
+        assert!(!line.contains(0.5, 0.0));
+    }
+}
@@ -0,0 +1,343 @@
+use crate::models::Correlation;
+use crate::processing::load_track;
+use crate::routes::ServerError;
+
+use tlms::trekkie::TrekkieRun;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::{error, info};
+use uuid::Uuid;
+
+/// telegrams further away than this from the closest gps fix in seconds are not correlated
+const MAX_FIX_DELTA: f64 = 30.0;
+
+/// receptions of the same telegram by several stations lie within this many seconds
+const DUPLICATE_WINDOW: f64 = 5.0;
+
+/// time delta to the closest fix in seconds at which the confidence has dropped to one half
+const CONFIDENCE_HALF_DELTA: f64 = 5.0;
+
+/// maximum amount of rows per insert statement
+const INSERT_CHUNK_SIZE: usize = 2000;
+
+/// R09 telegram of the run's vehicle
+struct Telegram {
+    id: i64,
+    time: NaiveDateTime,
+    reporting_point: i32,
+    junction: i32,
+    request_status: i16,
+}
+
+/// seconds between two timestamps
+fn seconds_between(from: NaiveDateTime, to: NaiveDateTime) -> f64 {
+    (to - from).num_milliseconds() as f64 / 1000.0
+}
+
+/// Position of the track at the given time together with the time delta to the closest gps fix
+/// and if the position was interpolated between two fixes. Returns None if the closest fix is
+/// too far away.
+fn position_at(
+    track: &[(NaiveDateTime, f64, f64)],
+    time: NaiveDateTime,
+) -> Option<(f64, f64, f64, bool)> {
+    let next = track.partition_point(|(fix_time, _, _)| *fix_time < time);
+
+    let before = next.checked_sub(1).map(|index| track[index]);
+    let after = track.get(next).copied();
+
+    match (before, after) {
+        (Some((before_time, before_lat, before_lon)), Some((after_time, after_lat, after_lon))) => {
+            let to_before = seconds_between(before_time, time);
+            let to_after = seconds_between(time, after_time);
+            let delta = to_before.min(to_after);
+            if delta > MAX_FIX_DELTA {
+                return None;
+            }
+
+            // raw tracks may contain several fixes with the same time
+            let share = match to_before + to_after {
+                total if total > 0.0 => to_before / total,
+                _ => 0.0,
+            };
+            Some((
+                before_lat + (after_lat - before_lat) * share,
+                before_lon + (after_lon - before_lon) * share,
+                delta,
+                true,
+            ))
+        }
+        (Some((fix_time, lat, lon)), None) | (None, Some((fix_time, lat, lon))) => {
+            let delta = seconds_between(fix_time, time).abs();
+            (delta <= MAX_FIX_DELTA).then_some((lat, lon, delta, false))
+        }
+        (None, None) => None,
+    }
+}
+
+/// accuracy of the raw gps fix closest to the given time
+fn closest_accuracy(fixes: &[(NaiveDateTime, Option<f64>)], time: NaiveDateTime) -> Option<f64> {
+    let next = fixes.partition_point(|(fix_time, _)| *fix_time < time);
+    let before = next.checked_sub(1).and_then(|index| fixes.get(index));
+
+    [before, fixes.get(next)]
+        .into_iter()
+        .flatten()
+        .min_by_key(|(fix_time, _)| (*fix_time - time).num_milliseconds().abs())
+        .and_then(|(_, accuracy)| *accuracy)
+}
+
+/// Confidence between 0 and 1 that the position is where the telegram was sent. It halves every
+/// five seconds away from the closest fix and once more if the position had to be taken from
+/// the first or last fix instead of being interpolated.
+fn confidence(delta: f64, interpolated: bool) -> f64 {
+    let confidence = 0.5f64.powf(delta / CONFIDENCE_HALF_DELTA);
+
+    if interpolated {
+        confidence
+    } else {
+        confidence / 2.0
+    }
+}
+
+/// loads the telegrams the vehicle of the run sent while it was recorded, the same telegram
+/// received by several stations is only kept once
+fn load_telegrams(
+    trekkie_run: &TrekkieRun,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<Telegram>, ServerError> {
+    use tlms::schema::r09_telegrams::dsl::*;
+
+    let margin = Duration::seconds(MAX_FIX_DELTA as i64);
+    let received = r09_telegrams
+        .filter(region.eq(trekkie_run.region))
+        .filter(line.eq(trekkie_run.line))
+        .filter(run_number.eq(trekkie_run.run))
+        .filter(time.between(
+            trekkie_run.start_time - margin,
+            trekkie_run.end_time + margin,
+        ))
+        .order(time.asc())
+        .select((id, time, reporting_point, junction, request_status))
+        .load::<(i64, NaiveDateTime, i32, i32, i16)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading r09 telegrams {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    let mut telegrams: Vec<Telegram> = Vec::with_capacity(received.len());
+    for (telegram_id, telegram_time, point, telegram_junction, status) in received {
+        let duplicate = telegrams
+            .iter()
+            .rev()
+            .take_while(|known| seconds_between(known.time, telegram_time) <= DUPLICATE_WINDOW)
+            .any(|known| known.reporting_point == point && known.request_status == status);
+
+        if !duplicate {
+            telegrams.push(Telegram {
+                id: telegram_id,
+                time: telegram_time,
+                reporting_point: point,
+                junction: telegram_junction,
+                request_status: status,
+            });
+        }
+    }
+
+    Ok(telegrams)
+}
+
+/// Matches the R09 telegrams of the run's vehicle with its track. For every telegram sent while
+/// the run was recorded the position at the telegram time is interpolated and stored as location
+/// candidate of the reporting point, replacing earlier candidates of this run. Afterwards the run
+/// is marked as correlated. Returns the amount of stored candidates or None if the run has no
+/// points to correlate, in which case it is left untouched.
+pub fn correlate_run(
+    trekkie_run: &TrekkieRun,
+    database_connection: &mut PgConnection,
+) -> Result<Option<usize>, ServerError> {
+    use crate::schema::trekkie_correlations::dsl::{trekkie_correlations, trekkie_run as run_id};
+    use tlms::schema::gps_points::dsl as gps;
+    use tlms::schema::trekkie_runs::dsl::{correlated, id as trekkie_id, trekkie_runs};
+
+    let track = load_track(trekkie_run.id, database_connection)?;
+    if track.is_empty() {
+        info!("run {} has no points to correlate", trekkie_run.id);
+        return Ok(None);
+    }
+
+    let fixes = gps::gps_points
+        .filter(gps::trekkie_run.eq(trekkie_run.id))
+        .order(gps::timestamp.asc())
+        .select((gps::timestamp, gps::accuracy))
+        .load::<(NaiveDateTime, Option<f64>)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading gps points {:?}", e);
+            ServerError::InternalError
+        })?;
+    let telegrams = load_telegrams(trekkie_run, database_connection)?;
+    let now = Utc::now().naive_utc();
+
+    let candidates: Vec<Correlation> = telegrams
+        .iter()
+        .filter_map(|telegram| {
+            let (lat, lon, delta, interpolated) = position_at(&track, telegram.time)?;
+
+            Some(Correlation {
+                trekkie_run: trekkie_run.id,
+                telegram: telegram.id,
+                time: telegram.time,
+                region: trekkie_run.region,
+                reporting_point: telegram.reporting_point,
+                junction: telegram.junction,
+                request_status: telegram.request_status,
+                lat,
+                lon,
+                fix_delta: delta,
+                confidence: confidence(delta, interpolated),
+                created_at: now,
+                accuracy: closest_accuracy(&fixes, telegram.time),
+            })
+        })
+        .collect();
+
+    database_connection
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(trekkie_correlations.filter(run_id.eq(trekkie_run.id))).execute(conn)?;
+            for chunk in candidates.chunks(INSERT_CHUNK_SIZE) {
+                diesel::insert_into(trekkie_correlations)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+            diesel::update(trekkie_runs.filter(trekkie_id.eq(trekkie_run.id)))
+                .set(correlated.eq(true))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .map_err(|e| {
+            error!("while trying to store correlation {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    info!(
+        "correlated run {} with {} of {} telegrams",
+        trekkie_run.id,
+        candidates.len(),
+        telegrams.len()
+    );
+
+    Ok(Some(candidates.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::tests::{assert_close, time};
+
+    fn assert_position(position: Option<(f64, f64, f64, bool)>, expected: (f64, f64, f64, bool)) {
+        let (lat, lon, delta, interpolated) = position.expect("no position");
+        assert_close(lat, expected.0);
+        assert_close(lon, expected.1);
+        assert_close(delta, expected.2);
+        assert_eq!(interpolated, expected.3);
+    }
+
+    #[test]
+    fn position_few_fixes() {
+        assert!(position_at(&[], time(12, 0, 0)).is_none());
+
+        // before and after a single fix its position is taken as it is
+        let track = [(time(12, 0, 0), 51.0, 13.0)];
+        assert_position(
+            position_at(&track, time(12, 0, 10)),
+            (51.0, 13.0, 10.0, false),
+        );
+        assert_position(
+            position_at(&track, time(11, 59, 30)),
+            (51.0, 13.0, 30.0, false),
+        );
+        assert!(position_at(&track, time(12, 0, 31)).is_none());
+    }
+
+    #[test]
+    fn interpolation() {
+        let track = [(time(12, 0, 0), 51.0, 13.0), (time(12, 0, 10), 51.1, 13.2)];
+
+        assert_position(
+            position_at(&track, time(12, 0, 2) + Duration::milliseconds(500)),
+            (51.025, 13.05, 2.5, true),
+        );
+        assert_position(
+            position_at(&track, time(12, 0, 8)),
+            (51.08, 13.16, 2.0, true),
+        );
+        // at a fix the fix itself is used
+        assert_position(
+            position_at(&track, time(12, 0, 10)),
+            (51.1, 13.2, 0.0, true),
+        );
+    }
+
+    #[test]
+    fn interpolation_gap() {
+        let track = [(time(12, 0, 0), 51.0, 13.0), (time(12, 1, 40), 51.1, 13.2)];
+
+        assert_position(
+            position_at(&track, time(12, 0, 20)),
+            (51.02, 13.04, 20.0, true),
+        );
+        // in the middle of the gap both fixes are too far away
+        assert!(position_at(&track, time(12, 0, 50)).is_none());
+    }
+
+    #[test]
+    fn duplicate_fix_times() {
+        let track = [
+            (time(12, 0, 0), 51.0, 13.0),
+            (time(12, 0, 10), 51.1, 13.2),
+            (time(12, 0, 10), 51.3, 13.4),
+            (time(12, 0, 20), 51.5, 13.6),
+        ];
+
+        // the first fix of a timestamp is used towards earlier times, the last one towards later
+        assert_position(
+            position_at(&track, time(12, 0, 5)),
+            (51.05, 13.1, 5.0, true),
+        );
+        assert_position(
+            position_at(&track, time(12, 0, 15)),
+            (51.4, 13.5, 5.0, true),
+        );
+        assert_position(
+            position_at(&track, time(12, 0, 10)),
+            (51.1, 13.2, 0.0, true),
+        );
+    }
+
+    #[test]
+    fn confidences() {
+        assert_close(confidence(0.0, true), 1.0);
+        assert_close(confidence(5.0, true), 0.5);
+        assert_close(confidence(10.0, true), 0.25);
+        assert_close(confidence(5.0, false), 0.25);
+        assert_close(confidence(0.0, false), 0.5);
+    }
+
+    #[test]
+    fn accuracy_of_closest_fix() {
+        let fixes = [
+            (time(12, 0, 0), Some(5.0)),
+            (time(12, 0, 10), None),
+            (time(12, 0, 20), Some(12.0)),
+        ];
+
+        assert_eq!(closest_accuracy(&[], time(12, 0, 0)), None);
+        assert_eq!(closest_accuracy(&fixes, time(11, 0, 0)), Some(5.0));
+        assert_eq!(closest_accuracy(&fixes, time(12, 0, 4)), Some(5.0));
+        assert_eq!(closest_accuracy(&fixes, time(12, 0, 6)), None);
+        assert_eq!(closest_accuracy(&fixes, time(12, 0, 16)), Some(12.0));
+        assert_eq!(closest_accuracy(&fixes, time(13, 0, 0)), Some(12.0));
+    }
+}
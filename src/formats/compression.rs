@@ -0,0 +1,89 @@
+use std::io::{self, BufRead, Read};
+
+/// Compression of an uploaded file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// detects the compression from the magic bytes at the beginning of the file
+    pub fn detect(head: &[u8]) -> Option<Compression> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// wraps the reader into a decoder for this compression
+    pub fn decoder<'a, R: BufRead + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        match self {
+            Compression::Gzip => Ok(Box::new(flate2::bufread::MultiGzDecoder::new(reader))),
+            Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+        }
+    }
+}
+
+/// removes the extension of the compression from a file name, so track.gpx.gz becomes track.gpx
+pub fn strip_extension(file_name: &str) -> &str {
+    match file_name.rsplit_once('.') {
+        Some((name, extension))
+            if ["gz", "gzip", "zst", "zstd"].contains(&extension.to_ascii_lowercase().as_str()) =>
+        {
+            name
+        }
+        _ => file_name,
+    }
+}
+
+/// Amount of bytes which may still be read from all files of one upload after decompression, so
+/// archives of many small files cannot expand beyond the maximum upload size either
+pub struct SizeBudget {
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl SizeBudget {
+    pub fn new(limit: u64) -> SizeBudget {
+        SizeBudget {
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    /// whether a file was cut off because the budget was used up
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+/// Fails as soon as more than the remaining budget was read, protects against uploads which
+/// decompress into huge files.
+pub struct LimitedReader<'a, R> {
+    inner: R,
+    budget: &'a mut SizeBudget,
+}
+
+impl<'a, R> LimitedReader<'a, R> {
+    pub fn new(inner: R, budget: &'a mut SizeBudget) -> LimitedReader<'a, R> {
+        LimitedReader { inner, budget }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+
+        if read as u64 > self.budget.remaining {
+            self.budget.exceeded = true;
+            return Err(io::Error::other("file exceeds the maximum upload size"));
+        }
+        self.budget.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
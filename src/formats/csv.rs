@@ -0,0 +1,230 @@
+use crate::formats::{format_time, parse_number, PointBuilder, TrackSink};
+
+use tlms::locations::gps::GpsPoint;
+
+use uuid::Uuid;
+
+use std::fmt::Write;
+use std::io::BufRead;
+
+/// column names of the exported csv file
+const HEADER: &str = "timestamp,lat,lon,elevation,accuracy,vertical_accuracy,speed,bearing";
+
+/// Serializes the points into a csv file with one row per point, unknown values stay empty.
+pub fn write(points: &[GpsPoint]) -> String {
+    let mut document = String::new();
+
+    document.push_str(HEADER);
+    document.push('\n');
+
+    for point in points {
+        let _ = writeln!(
+            document,
+            "{},{},{},{},{},{},{},{}",
+            format_time(&point.timestamp),
+            point.lat,
+            point.lon,
+            optional(point.elevation),
+            optional(point.accuracy),
+            optional(point.vertical_accuracy),
+            optional(point.speed),
+            optional(point.bearing),
+        );
+    }
+
+    document
+}
+
+fn optional(value: Option<f64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// column of an imported csv file
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Time,
+    Lat,
+    Lon,
+    Elevation,
+    Accuracy,
+    VerticalAccuracy,
+    Speed,
+    Bearing,
+    Unknown,
+}
+
+impl Column {
+    fn from_name(name: &str) -> Column {
+        match name.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "timestamp" | "time" | "datetime" | "date_time" => Column::Time,
+            "lat" | "latitude" => Column::Lat,
+            "lon" | "lng" | "long" | "longitude" => Column::Lon,
+            "elevation" | "ele" | "altitude" | "alt" => Column::Elevation,
+            "accuracy" | "horizontal_accuracy" => Column::Accuracy,
+            "vertical_accuracy" => Column::VerticalAccuracy,
+            "speed" => Column::Speed,
+            "bearing" | "course" | "heading" => Column::Bearing,
+            _ => Column::Unknown,
+        }
+    }
+}
+
+/// picks the delimiter which occurs most often in the header
+fn delimiter(header: &str) -> char {
+    [',', ';', '\t']
+        .into_iter()
+        .max_by_key(|delimiter| header.matches(*delimiter).count())
+        .unwrap_or(',')
+}
+
+/// splits a row into its values, delimiters inside double quotes are kept
+fn split_row(row: &str, delimiter: char) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut characters = row.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        match character {
+            '"' if quoted && characters.peek() == Some(&'"') => {
+                value.push('"');
+                characters.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => values.push(std::mem::take(&mut value)),
+            c => value.push(c),
+        }
+    }
+    values.push(value);
+
+    values
+}
+
+fn columns(header: &str) -> Vec<Column> {
+    split_row(header.trim_start_matches('\u{feff}'), delimiter(header))
+        .iter()
+        .map(|name| Column::from_name(name))
+        .collect()
+}
+
+/// checks if the line is a header with latitude and longitude columns
+pub fn is_header(line: &str) -> bool {
+    let columns = columns(line);
+    columns.contains(&Column::Lat) && columns.contains(&Column::Lon)
+}
+
+/// Reads a csv file with a header row. The columns are matched by name, so the files written by
+/// [`write`] as well as most spreadsheet exports can be imported. The time may be given as RFC
+/// 3339 or unix timestamp.
+pub fn read<R: BufRead, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    let mut lines = reader.lines();
+
+    let header = loop {
+        match lines.next() {
+            Some(Ok(line)) if line.trim().is_empty() => continue,
+            Some(Ok(line)) => break line,
+            Some(Err(e)) => return Err(format!("cannot read csv file: {}", e)),
+            None => return Ok(()),
+        }
+    };
+
+    if !is_header(&header) {
+        return Err("csv header needs lat and lon columns".to_string());
+    }
+
+    let delimiter = delimiter(&header);
+    let columns = columns(&header);
+
+    // spreadsheets which separate with semicolons usually write decimal commas
+    let number = |value: &str| match delimiter {
+        ',' => parse_number(value),
+        _ => parse_number(&value.replace(',', ".")),
+    };
+
+    for line in lines {
+        let line = line.map_err(|e| format!("cannot read csv file: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut point = PointBuilder::default();
+        for (column, value) in columns.iter().zip(split_row(&line, delimiter)) {
+            let value = value.trim();
+            match column {
+                Column::Time if !value.is_empty() => point.time = Some(value.to_string()),
+                Column::Lat => point.lat = number(value),
+                Column::Lon => point.lon = number(value),
+                Column::Elevation => point.elevation = number(value),
+                Column::Accuracy => point.accuracy = number(value),
+                Column::VerticalAccuracy => point.vertical_accuracy = number(value),
+                Column::Speed => point.speed = number(value),
+                Column::Bearing => point.bearing = number(value),
+                _ => {}
+            }
+        }
+        point.finish(run_id, sink);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::tests::{assert_close, time, CollectingSink};
+
+    #[test]
+    fn read_comma_separated() {
+        let file = "\
+timestamp,lat,lon,elevation,speed
+2024-05-01T12:00:00Z,51.05,13.74,112.5,4.2
+
+1714564801,51.06,13.75,,
+1714564802000,95.0,13.75,,
+";
+        let mut sink = CollectingSink::default();
+        read(file.as_bytes(), Uuid::nil(), &mut sink).unwrap();
+
+        assert_eq!(sink.points.len(), 2);
+        assert_eq!(sink.points[0].timestamp, time(12, 0, 0));
+        assert_close(sink.points[0].lat, 51.05);
+        assert_close(sink.points[0].lon, 13.74);
+        assert_eq!(sink.points[0].elevation, Some(112.5));
+        assert_eq!(sink.points[0].speed, Some(4.2));
+        assert_eq!(sink.points[1].timestamp, time(12, 0, 1));
+        assert_eq!(sink.points[1].elevation, None);
+        assert_eq!(sink.skipped, ["missing or invalid coordinates"]);
+    }
+
+    #[test]
+    fn read_spreadsheet_export() {
+        let file = "\u{feff}\"Time\";\"Latitude\";\"Longitude\";\"Note\"
+2024-05-01 12:00:00;51,05;13,74;\"stop; platform 2\"
+";
+        let mut sink = CollectingSink::default();
+        read(file.as_bytes(), Uuid::nil(), &mut sink).unwrap();
+
+        assert_eq!(sink.points.len(), 1);
+        assert_eq!(sink.points[0].timestamp, time(12, 0, 0));
+        assert_close(sink.points[0].lat, 51.05);
+        assert_close(sink.points[0].lon, 13.74);
+    }
+
+    #[test]
+    fn header() {
+        assert!(is_header("time,lat,lng"));
+        assert!(is_header("latitude\tlongitude"));
+        assert!(!is_header("time,lat,elevation"));
+
+        let mut sink = CollectingSink::default();
+        assert!(read("time,x,y\n".as_bytes(), Uuid::nil(), &mut sink).is_err());
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(
+            split_row("a,\"b,c\",\"say \"\"hi\"\"\"", ','),
+            ["a", "b,c", "say \"hi\""]
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use crate::formats::{valid_coordinates, TrackSink};
+
+use tlms::locations::gps::InsertGpsPoint;
+
+use chrono::DateTime;
+use uuid::Uuid;
+
+use std::collections::HashMap;
+use std::io::Read;
+
+/// seconds between the unix epoch and the fit epoch 1989-12-31T00:00:00Z
+const FIT_EPOCH: i64 = 631_065_600;
+
+/// global message number of the record messages which carry the position samples
+const RECORD_MESSAGE: u16 = 20;
+
+// field numbers of the record message, 253 is the timestamp of every message type
+const TIMESTAMP: u8 = 253;
+const POSITION_LAT: u8 = 0;
+const POSITION_LONG: u8 = 1;
+const ALTITUDE: u8 = 2;
+const SPEED: u8 = 6;
+const GPS_ACCURACY: u8 = 31;
+const ENHANCED_SPEED: u8 = 73;
+const ENHANCED_ALTITUDE: u8 = 78;
+
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+}
+
+/// layout of the data messages of one local message type
+struct MessageDefinition {
+    big_endian: bool,
+    global: u16,
+    fields: Vec<FieldDefinition>,
+    developer_size: usize,
+}
+
+/// reads the data records while keeping track of the data size given in the header
+struct FitReader<R> {
+    reader: R,
+    remaining: u64,
+}
+
+impl<R: Read> FitReader<R> {
+    fn bytes(&mut self, count: usize) -> Result<Vec<u8>, String> {
+        if count as u64 > self.remaining {
+            return Err("record exceeds the data size of the file".to_string());
+        }
+
+        let mut buffer = vec![0; count];
+        self.reader
+            .read_exact(&mut buffer)
+            .map_err(|e| format!("truncated fit file: {}", e))?;
+        self.remaining -= count as u64;
+
+        Ok(buffer)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+}
+
+/// decodes an unsigned integer field, the invalid value of the fit base types is all bits set
+fn unsigned(bytes: &[u8], big_endian: bool) -> Option<u32> {
+    let value = match (bytes.len(), big_endian) {
+        (1, _) => bytes[0] as u32,
+        (2, false) => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+        (2, true) => u16::from_be_bytes([bytes[0], bytes[1]]) as u32,
+        (4, false) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        (4, true) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        _ => return None,
+    };
+
+    let invalid = match bytes.len() {
+        1 => 0xFF,
+        2 => 0xFFFF,
+        _ => 0xFFFF_FFFF,
+    };
+
+    (value != invalid).then_some(value)
+}
+
+/// decodes a position in semicircles into degrees
+fn semicircles(bytes: &[u8], big_endian: bool) -> Option<f64> {
+    if bytes.len() != 4 {
+        return None;
+    }
+
+    // the invalid value of sint32 is 0x7FFFFFFF, which unsigned() does not filter
+    unsigned(bytes, big_endian)
+        .map(|value| value as i32)
+        .filter(|value| *value != i32::MAX)
+        .map(|value| value as f64 * 180.0 / 2f64.powi(31))
+}
+
+/// values of a record message
+#[derive(Default)]
+struct Record {
+    timestamp: Option<u32>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    altitude: Option<f64>,
+    enhanced_altitude: Option<f64>,
+    speed: Option<f64>,
+    enhanced_speed: Option<f64>,
+    accuracy: Option<f64>,
+}
+
+impl Record {
+    fn set(&mut self, field: u8, bytes: &[u8], big_endian: bool) {
+        // altitudes are stored with scale 5 and offset 500, speeds with scale 1000
+        let altitude = || unsigned(bytes, big_endian).map(|value| value as f64 / 5.0 - 500.0);
+        let speed = || unsigned(bytes, big_endian).map(|value| value as f64 / 1000.0);
+
+        match field {
+            POSITION_LAT => self.lat = semicircles(bytes, big_endian),
+            POSITION_LONG => self.lon = semicircles(bytes, big_endian),
+            ALTITUDE => self.altitude = altitude(),
+            ENHANCED_ALTITUDE => self.enhanced_altitude = altitude(),
+            SPEED => self.speed = speed(),
+            ENHANCED_SPEED => self.enhanced_speed = speed(),
+            GPS_ACCURACY => self.accuracy = unsigned(bytes, big_endian).map(f64::from),
+            _ => {}
+        }
+    }
+
+    fn finish<S: TrackSink>(self, run_id: Uuid, sink: &mut S) {
+        let timestamp = match self
+            .timestamp
+            .and_then(|timestamp| DateTime::from_timestamp(FIT_EPOCH + timestamp as i64, 0))
+        {
+            Some(timestamp) => timestamp.naive_utc(),
+            None => {
+                sink.skip("record has no timestamp".to_string());
+                return;
+            }
+        };
+
+        let (lat, lon) = match (self.lat, self.lon) {
+            (Some(lat), Some(lon)) if valid_coordinates(lat, lon) => (lat, lon),
+            _ => {
+                sink.skip("record has no position".to_string());
+                return;
+            }
+        };
+
+        sink.push(InsertGpsPoint {
+            id: None,
+            trekkie_run: run_id,
+            timestamp,
+            lat,
+            lon,
+            elevation: self.enhanced_altitude.or(self.altitude),
+            accuracy: self.accuracy,
+            vertical_accuracy: None,
+            bearing: None,
+            speed: self.enhanced_speed.or(self.speed),
+        });
+    }
+}
+
+/// Reads the record messages of a Garmin FIT activity file. Every record message is one point,
+/// records without position, which devices write before they have a fix or indoors, are
+/// reported as skipped. Developer fields and all other messages are ignored.
+pub fn read<R: Read, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    let mut reader = FitReader {
+        reader,
+        remaining: u64::MAX,
+    };
+
+    let header_size = reader.byte()? as usize;
+    if header_size < 12 {
+        return Err("invalid fit header".to_string());
+    }
+    let header = reader.bytes(header_size - 1)?;
+    if &header[7..11] != b".FIT" {
+        return Err("file has no fit signature".to_string());
+    }
+    reader.remaining = u32::from_le_bytes([header[3], header[4], header[5], header[6]]) as u64;
+
+    let mut definitions: HashMap<u8, MessageDefinition> = HashMap::new();
+    let mut last_timestamp: Option<u32> = None;
+
+    while reader.remaining > 0 {
+        let record_header = reader.byte()?;
+
+        let (local, mut timestamp) = if record_header & 0x80 != 0 {
+            // compressed timestamp header, the five bits are added to the last full timestamp
+            let offset = (record_header & 0x1F) as u32;
+            let timestamp = last_timestamp.map(|last| {
+                let rolled_over = offset < (last & 0x1F);
+                (last & !0x1F) + offset + if rolled_over { 0x20 } else { 0 }
+            });
+            ((record_header >> 5) & 0x03, timestamp)
+        } else if record_header & 0x40 != 0 {
+            let fixed = reader.bytes(5)?;
+            let big_endian = fixed[1] == 1;
+            let global = if big_endian {
+                u16::from_be_bytes([fixed[2], fixed[3]])
+            } else {
+                u16::from_le_bytes([fixed[2], fixed[3]])
+            };
+
+            let mut fields = Vec::new();
+            for _ in 0..fixed[4] {
+                let field = reader.bytes(3)?;
+                fields.push(FieldDefinition {
+                    number: field[0],
+                    size: field[1] as usize,
+                });
+            }
+
+            let mut developer_size = 0;
+            if record_header & 0x20 != 0 {
+                for _ in 0..reader.byte()? {
+                    developer_size += reader.bytes(3)?[1] as usize;
+                }
+            }
+
+            definitions.insert(
+                record_header & 0x0F,
+                MessageDefinition {
+                    big_endian,
+                    global,
+                    fields,
+                    developer_size,
+                },
+            );
+            continue;
+        } else {
+            (record_header & 0x0F, None)
+        };
+
+        let definition = definitions
+            .get(&local)
+            .ok_or_else(|| format!("data message of undefined local message {}", local))?;
+
+        let mut record = Record::default();
+        for field in &definition.fields {
+            let bytes = reader.bytes(field.size)?;
+            if field.number == TIMESTAMP {
+                timestamp = unsigned(&bytes, definition.big_endian).or(timestamp);
+            } else if definition.global == RECORD_MESSAGE {
+                record.set(field.number, &bytes, definition.big_endian);
+            }
+        }
+        reader.bytes(definition.developer_size)?;
+
+        if timestamp.is_some() {
+            last_timestamp = timestamp;
+        }
+
+        if definition.global == RECORD_MESSAGE {
+            record.timestamp = timestamp;
+            record.finish(run_id, sink);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::tests::{assert_close, CollectingSink};
+
+    use chrono::NaiveDate;
+
+    /// 2021-09-08T01:46:40Z in seconds since the fit epoch
+    const START: u32 = 1_000_000_000;
+    /// 51.0 and 13.7 degrees in semicircles
+    const LAT: i32 = 608_453_700;
+    const LON: i32 = 163_447_367;
+
+    /// wraps the records into a fit file with a 14 byte header, the crcs are not checked
+    fn file(records: &[u8]) -> Vec<u8> {
+        let mut file = vec![14, 0x10, 0x08, 0x08];
+        file.extend((records.len() as u32).to_le_bytes());
+        file.extend(b".FIT");
+        file.extend([0, 0]);
+        file.extend(records);
+        file.extend([0, 0]);
+        file
+    }
+
+    fn records() -> Vec<u8> {
+        let mut records = Vec::new();
+
+        // local message 0: record with timestamp, position, altitude and speed
+        records.extend([0x40, 0, 0, 20, 0, 5]);
+        records.extend([253, 4, 0x86, 0, 4, 0x85, 1, 4, 0x85, 2, 2, 0x84, 6, 2, 0x84]);
+        records.push(0x00);
+        records.extend(START.to_le_bytes());
+        records.extend(LAT.to_le_bytes());
+        records.extend(LON.to_le_bytes());
+        records.extend(3000u16.to_le_bytes());
+        records.extend(5000u16.to_le_bytes());
+
+        // local message 1: record with position only, used with compressed timestamps
+        records.extend([0x41, 0, 0, 20, 0, 2]);
+        records.extend([0, 4, 0x85, 1, 4, 0x85]);
+        records.push(0x80 | (1 << 5) | 5);
+        records.extend(LAT.to_le_bytes());
+        records.extend(LON.to_le_bytes());
+
+        // record without fix
+        records.push(0x80 | (1 << 5) | 6);
+        records.extend(i32::MAX.to_le_bytes());
+        records.extend(i32::MAX.to_le_bytes());
+
+        records
+    }
+
+    #[test]
+    fn read_records() {
+        let mut sink = CollectingSink::default();
+        read(file(&records()).as_slice(), Uuid::nil(), &mut sink).unwrap();
+
+        let start = NaiveDate::from_ymd_opt(2021, 9, 8)
+            .unwrap()
+            .and_hms_opt(1, 46, 40)
+            .unwrap();
+
+        assert_eq!(sink.points.len(), 2);
+        assert_eq!(sink.points[0].timestamp, start);
+        assert_close(sink.points[0].lat, 51.0);
+        assert_close(sink.points[0].lon, 13.7);
+        assert_eq!(sink.points[0].elevation, Some(100.0));
+        assert_eq!(sink.points[0].speed, Some(5.0));
+        assert_eq!(
+            sink.points[1].timestamp,
+            start + chrono::Duration::seconds(5)
+        );
+        assert_eq!(sink.points[1].elevation, None);
+        assert_eq!(sink.skipped, ["record has no position"]);
+    }
+
+    #[test]
+    fn big_endian_definition() {
+        let mut records = vec![0x40, 0, 1, 0, 20, 2];
+        records.extend([0, 4, 0x85, 1, 4, 0x85]);
+        records.push(0x00);
+        records.extend(LAT.to_be_bytes());
+        records.extend(LON.to_be_bytes());
+
+        let mut sink = CollectingSink::default();
+        read(file(&records).as_slice(), Uuid::nil(), &mut sink).unwrap();
+
+        assert!(sink.points.is_empty());
+        assert_eq!(sink.skipped, ["record has no timestamp"]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut sink = CollectingSink::default();
+
+        let mut other = file(&records());
+        other[8..12].copy_from_slice(b".GPX");
+        assert_eq!(
+            read(other.as_slice(), Uuid::nil(), &mut sink),
+            Err("file has no fit signature".to_string())
+        );
+
+        let truncated = file(&records());
+        assert!(read(&truncated[..40], Uuid::nil(), &mut sink).is_err());
+
+        assert!(read(&[0x40, 0x00][..], Uuid::nil(), &mut sink).is_err());
+    }
+}
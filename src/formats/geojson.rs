@@ -0,0 +1,183 @@
+use crate::formats::{format_time, track_name, PointBuilder, TrackSink};
+
+use tlms::locations::gps::GpsPoint;
+use tlms::trekkie::TrekkieRun;
+
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use std::io::BufRead;
+
+/// Serializes the points into a GeoJSON FeatureCollection. The first feature is the whole track
+/// as LineString, followed by one Point feature per gps point with its measurements as properties.
+pub fn write(trekkie_run: &TrekkieRun, points: &[GpsPoint]) -> String {
+    let mut features: Vec<Value> = Vec::with_capacity(points.len() + 1);
+
+    features.push(json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": points.iter().map(coordinate).collect::<Vec<Value>>(),
+        },
+        "properties": {
+            "name": track_name(trekkie_run),
+            "trekkie_run": trekkie_run.id,
+            "line": trekkie_run.line,
+            "run": trekkie_run.run,
+            "region": trekkie_run.region,
+            "coordTimes": points
+                .iter()
+                .map(|point| format_time(&point.timestamp))
+                .collect::<Vec<String>>(),
+        },
+    }));
+
+    features.extend(points.iter().map(|point| {
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "Point",
+                "coordinates": coordinate(point),
+            },
+            "properties": {
+                "timestamp": format_time(&point.timestamp),
+                "elevation": point.elevation,
+                "accuracy": point.accuracy,
+                "vertical_accuracy": point.vertical_accuracy,
+                "speed": point.speed,
+                "bearing": point.bearing,
+            },
+        })
+    }));
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+    .to_string()
+}
+
+/// geojson position, the elevation is left out if it is unknown
+fn coordinate(point: &GpsPoint) -> Value {
+    match point.elevation {
+        Some(elevation) => json!([point.lon, point.lat, elevation]),
+        None => json!([point.lon, point.lat]),
+    }
+}
+
+/// text of a property which may be stored as string or number
+fn text_property(properties: &Map<String, Value>, names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| match properties.get(*name) {
+        Some(Value::String(text)) => Some(text.clone()),
+        Some(Value::Number(number)) => Some(number.to_string()),
+        _ => None,
+    })
+}
+
+fn number_property(properties: &Map<String, Value>, names: &[&str]) -> Option<f64> {
+    names
+        .iter()
+        .find_map(|name| properties.get(*name).and_then(Value::as_f64))
+}
+
+/// sets position and elevation from a geojson position
+fn set_position(point: &mut PointBuilder, position: &Value) {
+    let values = position.as_array().map(Vec::as_slice).unwrap_or_default();
+
+    point.lon = values.first().and_then(Value::as_f64);
+    point.lat = values.get(1).and_then(Value::as_f64);
+    point.elevation = values.get(2).and_then(Value::as_f64);
+}
+
+/// adds the positions of a line, their times are taken from the coordTimes property
+fn read_line<S: TrackSink>(positions: &Value, times: Option<&Value>, run_id: Uuid, sink: &mut S) {
+    let times = times.and_then(Value::as_array);
+
+    for (index, position) in positions.as_array().into_iter().flatten().enumerate() {
+        let mut point = PointBuilder {
+            time: times
+                .and_then(|times| times.get(index))
+                .and_then(|time| match time {
+                    Value::String(text) => Some(text.clone()),
+                    Value::Number(number) => Some(number.to_string()),
+                    _ => None,
+                }),
+            ..Default::default()
+        };
+        set_position(&mut point, position);
+        point.finish(run_id, sink);
+    }
+}
+
+fn geometry(feature: &Value) -> &Value {
+    feature.get("geometry").unwrap_or(feature)
+}
+
+fn is_point(feature: &Value) -> bool {
+    geometry(feature).get("type").and_then(Value::as_str) == Some("Point")
+}
+
+/// adds either the point or the line points of a feature
+fn read_feature<S: TrackSink>(feature: &Value, use_points: bool, run_id: Uuid, sink: &mut S) {
+    let empty = Map::new();
+    let properties = feature
+        .get("properties")
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+    let geometry = geometry(feature);
+    let coordinates = geometry.get("coordinates").unwrap_or(&Value::Null);
+
+    match geometry.get("type").and_then(Value::as_str) {
+        Some("Point") if use_points => {
+            let mut point = PointBuilder {
+                time: text_property(properties, &["timestamp", "time", "when"]),
+                accuracy: number_property(properties, &["accuracy"]),
+                vertical_accuracy: number_property(properties, &["vertical_accuracy"]),
+                speed: number_property(properties, &["speed"]),
+                bearing: number_property(properties, &["bearing", "course", "heading"]),
+                ..Default::default()
+            };
+            set_position(&mut point, coordinates);
+            if point.elevation.is_none() {
+                point.elevation = number_property(properties, &["elevation", "altitude"]);
+            }
+            point.finish(run_id, sink);
+        }
+        Some("LineString") if !use_points => {
+            read_line(coordinates, properties.get("coordTimes"), run_id, sink);
+        }
+        Some("MultiLineString") if !use_points => {
+            let times = properties.get("coordTimes").and_then(Value::as_array);
+            for (index, line) in coordinates.as_array().into_iter().flatten().enumerate() {
+                read_line(line, times.and_then(|times| times.get(index)), run_id, sink);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Reads Point features with a timestamp property and LineStrings with coordTimes. Files
+/// exported by [`write`] contain the track twice, so the lines are only used if there are no
+/// point features.
+pub fn read<R: BufRead, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    let document: Value =
+        serde_json::from_reader(reader).map_err(|e| format!("invalid json: {}", e))?;
+
+    let features: Vec<&Value> = match document.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => document
+            .get("features")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .collect(),
+        Some(_) => vec![&document],
+        None => return Err("document is not geojson".to_string()),
+    };
+
+    let use_points = features.iter().any(|feature| is_point(feature));
+    for feature in features {
+        read_feature(feature, use_points, run_id, sink);
+    }
+
+    Ok(())
+}
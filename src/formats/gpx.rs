@@ -0,0 +1,189 @@
+use crate::formats::{escape_xml, format_time, parse_number, track_name, PointBuilder, TrackSink};
+
+use tlms::locations::gps::GpsPoint;
+use tlms::trekkie::TrekkieRun;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use uuid::Uuid;
+
+use std::fmt::Write;
+use std::io::BufRead;
+
+/// Serializes the points into a GPX 1.1 document with a single track and segment. Speed and
+/// bearing are written as Garmin TrackPointExtension, the horizontal accuracy in meters as
+/// trekkie accuracy extension, because hdop is a unitless dilution of precision.
+pub fn write(trekkie_run: &TrekkieRun, points: &[GpsPoint]) -> String {
+    let mut document = String::new();
+
+    document.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    document.push_str(concat!(
+        "<gpx version=\"1.1\" creator=\"trekkie\" ",
+        "xmlns=\"http://www.topografix.com/GPX/1/1\" ",
+        "xmlns:gpxtpx=\"http://www.garmin.com/xmlschemas/TrackPointExtension/v2\" ",
+        "xmlns:trekkie=\"https://github.com/tlm-solutions/trekkie\">\n"
+    ));
+    document.push_str("  <trk>\n");
+    let _ = writeln!(
+        document,
+        "    <name>{}</name>",
+        escape_xml(&track_name(trekkie_run))
+    );
+    document.push_str("    <trkseg>\n");
+
+    for point in points {
+        let _ = writeln!(
+            document,
+            "      <trkpt lat=\"{}\" lon=\"{}\">",
+            point.lat, point.lon
+        );
+        if let Some(elevation) = point.elevation {
+            let _ = writeln!(document, "        <ele>{}</ele>", elevation);
+        }
+        let _ = writeln!(
+            document,
+            "        <time>{}</time>",
+            format_time(&point.timestamp)
+        );
+
+        if point.speed.is_some() || point.bearing.is_some() || point.accuracy.is_some() {
+            document.push_str("        <extensions>\n");
+            if point.speed.is_some() || point.bearing.is_some() {
+                document.push_str("          <gpxtpx:TrackPointExtension>\n");
+                if let Some(speed) = point.speed {
+                    let _ = writeln!(
+                        document,
+                        "            <gpxtpx:speed>{}</gpxtpx:speed>",
+                        speed
+                    );
+                }
+                if let Some(bearing) = point.bearing {
+                    let _ = writeln!(
+                        document,
+                        "            <gpxtpx:course>{}</gpxtpx:course>",
+                        bearing
+                    );
+                }
+                document.push_str("          </gpxtpx:TrackPointExtension>\n");
+            }
+            if let Some(accuracy) = point.accuracy {
+                let _ = writeln!(
+                    document,
+                    "          <trekkie:accuracy>{}</trekkie:accuracy>",
+                    accuracy
+                );
+            }
+            document.push_str("        </extensions>\n");
+        }
+
+        document.push_str("      </trkpt>\n");
+    }
+
+    document.push_str("    </trkseg>\n");
+    document.push_str("  </trk>\n");
+    document.push_str("</gpx>\n");
+
+    document
+}
+
+/// starts a point from the lat and lon attributes of a track point
+fn start_point(element: &BytesStart) -> PointBuilder {
+    let coordinate = |name: &str| {
+        element
+            .try_get_attribute(name)
+            .ok()
+            .flatten()
+            .and_then(|attribute| attribute.unescape_value().ok())
+            .and_then(|value| parse_number(&value))
+    };
+
+    PointBuilder {
+        lat: coordinate("lat"),
+        lon: coordinate("lon"),
+        ..Default::default()
+    }
+}
+
+/// stores the text of a child element of a track point, extensions are matched by their local
+/// name so the namespace prefix chosen by the exporting app does not matter
+fn set_value(point: &mut PointBuilder, element: &[u8], in_extensions: bool, text: &str) {
+    match (element, in_extensions) {
+        (b"ele", false) => point.elevation = parse_number(text),
+        (b"time", false) => point.time = Some(text.to_string()),
+        // gpx 1.0 speed and course or gpxtpx:speed and gpxtpx:course
+        (b"speed", _) => point.speed = parse_number(text),
+        (b"course", _) | (b"bearing", true) | (b"heading", true) => {
+            point.bearing = parse_number(text)
+        }
+        // osmand stores the horizontal accuracy in meters as hdop extension
+        (b"hdop", true) | (b"accuracy", true) => point.accuracy = parse_number(text),
+        (b"vdop", true) | (b"vertical_accuracy", true) => {
+            point.vertical_accuracy = parse_number(text)
+        }
+        _ => {}
+    }
+}
+
+/// Reads the track points of a GPX 1.0 or 1.1 document. Points without usable time or
+/// coordinates are reported as skipped, only malformed xml fails the whole document.
+pub fn read<R: BufRead, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut buffer = Vec::new();
+    let mut elements: Vec<Vec<u8>> = Vec::new();
+    let mut pending: Option<PointBuilder> = None;
+    let mut is_gpx = false;
+
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) => {
+                let name = element.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"gpx" => is_gpx = true,
+                    b"trkpt" => pending = Some(start_point(&element)),
+                    _ => {}
+                }
+                elements.push(name);
+            }
+            Ok(Event::Empty(element)) => {
+                if element.local_name().as_ref() == b"trkpt" {
+                    start_point(&element).finish(run_id, sink);
+                }
+            }
+            Ok(Event::Text(text)) => {
+                if let (Some(point), Some(element)) = (pending.as_mut(), elements.last()) {
+                    let text = text.unescape().map_err(|e| {
+                        format!("invalid text at {}: {}", reader.buffer_position(), e)
+                    })?;
+                    let in_extensions = elements.iter().any(|name| name == b"extensions");
+                    set_value(point, element, in_extensions, &text);
+                }
+            }
+            Ok(Event::End(element)) => {
+                if element.local_name().as_ref() == b"trkpt" {
+                    if let Some(point) = pending.take() {
+                        point.finish(run_id, sink);
+                    }
+                }
+                elements.pop();
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!(
+                    "malformed xml at {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+        }
+        buffer.clear();
+    }
+
+    if !is_gpx {
+        return Err("document has no gpx element".to_string());
+    }
+
+    Ok(())
+}
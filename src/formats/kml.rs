@@ -0,0 +1,238 @@
+use crate::formats::{escape_xml, format_time, parse_number, track_name, PointBuilder, TrackSink};
+
+use tlms::locations::gps::GpsPoint;
+use tlms::trekkie::TrekkieRun;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use uuid::Uuid;
+
+use std::fmt::Write;
+use std::io::BufRead;
+
+/// Serializes the points into a KML document. The document contains the whole track as
+/// LineString and a folder with one timestamped placemark per point, which carries the optional
+/// measurements as extended data.
+pub fn write(trekkie_run: &TrekkieRun, points: &[GpsPoint]) -> String {
+    let mut document = String::new();
+
+    document.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    document.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n");
+    document.push_str("  <Document>\n");
+    let _ = writeln!(
+        document,
+        "    <name>{}</name>",
+        escape_xml(&track_name(trekkie_run))
+    );
+
+    document.push_str("    <Placemark>\n");
+    document.push_str("      <name>track</name>\n");
+    document.push_str("      <LineString>\n");
+    document.push_str("        <altitudeMode>absolute</altitudeMode>\n");
+    document.push_str("        <coordinates>\n");
+    for point in points {
+        let _ = writeln!(document, "          {}", coordinate(point));
+    }
+    document.push_str("        </coordinates>\n");
+    document.push_str("      </LineString>\n");
+    document.push_str("    </Placemark>\n");
+
+    document.push_str("    <Folder>\n");
+    document.push_str("      <name>points</name>\n");
+    for point in points {
+        document.push_str("      <Placemark>\n");
+        let _ = writeln!(
+            document,
+            "        <TimeStamp><when>{}</when></TimeStamp>",
+            format_time(&point.timestamp)
+        );
+
+        let measurements = [
+            ("accuracy", point.accuracy),
+            ("vertical_accuracy", point.vertical_accuracy),
+            ("speed", point.speed),
+            ("bearing", point.bearing),
+        ];
+        if measurements.iter().any(|(_, value)| value.is_some()) {
+            document.push_str("        <ExtendedData>\n");
+            for (name, value) in measurements {
+                if let Some(value) = value {
+                    let _ = writeln!(
+                        document,
+                        "          <Data name=\"{}\"><value>{}</value></Data>",
+                        name, value
+                    );
+                }
+            }
+            document.push_str("        </ExtendedData>\n");
+        }
+
+        let _ = writeln!(
+            document,
+            "        <Point><coordinates>{}</coordinates></Point>",
+            coordinate(point)
+        );
+        document.push_str("      </Placemark>\n");
+    }
+    document.push_str("    </Folder>\n");
+
+    document.push_str("  </Document>\n");
+    document.push_str("</kml>\n");
+
+    document
+}
+
+/// kml coordinate tuple, the elevation is left out if it is unknown
+fn coordinate(point: &GpsPoint) -> String {
+    match point.elevation {
+        Some(elevation) => format!("{},{},{}", point.lon, point.lat, elevation),
+        None => format!("{},{}", point.lon, point.lat),
+    }
+}
+
+/// sets position and elevation from a kml coordinate tuple, the values are separated by commas
+/// in coordinates elements and by spaces in gx:coord elements
+fn set_coordinate(point: &mut PointBuilder, text: &str) {
+    let mut values = text
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty());
+
+    point.lon = values.next().and_then(parse_number);
+    point.lat = values.next().and_then(parse_number);
+    point.elevation = values.next().and_then(parse_number);
+}
+
+/// stores a value of the extended data written by [`write`]
+fn set_data(point: &mut PointBuilder, name: &str, text: &str) {
+    match name {
+        "accuracy" => point.accuracy = parse_number(text),
+        "vertical_accuracy" => point.vertical_accuracy = parse_number(text),
+        "speed" => point.speed = parse_number(text),
+        "bearing" => point.bearing = parse_number(text),
+        _ => {}
+    }
+}
+
+/// Reads the samples of gx:Track elements, as exported by OsmAnd and Google, and placemarks
+/// with a timestamped point. LineStrings carry no time and are ignored.
+pub fn read<R: BufRead, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut buffer = Vec::new();
+    let mut elements: Vec<Vec<u8>> = Vec::new();
+    let mut is_kml = false;
+
+    // placemark which is currently read and whether it contains a point
+    let mut placemark: Option<(PointBuilder, bool)> = None;
+    let mut data_name: Option<String> = None;
+
+    // times and coordinates of the current gx:Track
+    let mut track_times: Vec<String> = Vec::new();
+    let mut track_coordinates: Vec<String> = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) => {
+                let name = element.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"kml" => is_kml = true,
+                    b"Placemark" => placemark = Some((PointBuilder::default(), false)),
+                    b"Point" => {
+                        if let Some((_, has_point)) = placemark.as_mut() {
+                            *has_point = true;
+                        }
+                    }
+                    b"Data" => {
+                        data_name = element
+                            .try_get_attribute("name")
+                            .ok()
+                            .flatten()
+                            .and_then(|attribute| attribute.unescape_value().ok())
+                            .map(|value| value.into_owned());
+                    }
+                    _ => {}
+                }
+                elements.push(name);
+            }
+            Ok(Event::Text(text)) => {
+                let text = text
+                    .unescape()
+                    .map_err(|e| format!("invalid text at {}: {}", reader.buffer_position(), e))?;
+                let parent = elements.len().checked_sub(2).map(|index| &elements[index]);
+                let in_track = elements.iter().any(|name| name == b"Track");
+
+                match elements.last().map(|name| name.as_slice()) {
+                    Some(b"when") if in_track => track_times.push(text.into_owned()),
+                    Some(b"coord") if in_track => track_coordinates.push(text.into_owned()),
+                    Some(b"when") => {
+                        if let (Some((point, _)), Some(b"TimeStamp")) =
+                            (placemark.as_mut(), parent.map(|name| name.as_slice()))
+                        {
+                            point.time = Some(text.into_owned());
+                        }
+                    }
+                    Some(b"coordinates") => {
+                        if let (Some((point, _)), Some(b"Point")) =
+                            (placemark.as_mut(), parent.map(|name| name.as_slice()))
+                        {
+                            set_coordinate(point, &text);
+                        }
+                    }
+                    Some(b"value") => {
+                        if let (Some((point, _)), Some(name)) =
+                            (placemark.as_mut(), data_name.as_deref())
+                        {
+                            set_data(point, name, &text);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::End(element)) => {
+                match element.local_name().as_ref() {
+                    b"Track" => {
+                        let samples = track_times.len().max(track_coordinates.len());
+                        let mut times = track_times.drain(..);
+                        let mut coordinates = track_coordinates.drain(..);
+
+                        for _ in 0..samples {
+                            let mut point = PointBuilder {
+                                time: times.next(),
+                                ..Default::default()
+                            };
+                            if let Some(coordinate) = coordinates.next() {
+                                set_coordinate(&mut point, &coordinate);
+                            }
+                            point.finish(run_id, sink);
+                        }
+                    }
+                    b"Placemark" => {
+                        if let Some((point, true)) = placemark.take() {
+                            point.finish(run_id, sink);
+                        }
+                    }
+                    b"Data" => data_name = None,
+                    _ => {}
+                }
+                elements.pop();
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!(
+                    "malformed xml at {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+        }
+        buffer.clear();
+    }
+
+    if !is_kml {
+        return Err("document has no kml element".to_string());
+    }
+
+    Ok(())
+}
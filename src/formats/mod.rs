@@ -0,0 +1,492 @@
+pub mod compression;
+pub mod csv;
+pub mod fit;
+pub mod geojson;
+pub mod gpx;
+pub mod kml;
+pub mod nmea;
+pub mod tcx;
+
+use tlms::locations::gps::{GpsPoint, InsertGpsPoint};
+use tlms::trekkie::TrekkieRun;
+
+use chrono::{DateTime, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use compression::{strip_extension, Compression, LimitedReader, SizeBudget};
+
+use std::io::{BufRead, BufReader, Read};
+
+/// buffer size used when reading uploaded files, the format is detected from its first fill
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// File formats a gps track can be exported to
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackFormat {
+    Gpx,
+    GeoJson,
+    Kml,
+    Csv,
+}
+
+impl TrackFormat {
+    /// mime type which is send back to the client
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "application/gpx+xml",
+            TrackFormat::GeoJson => "application/geo+json",
+            TrackFormat::Kml => "application/vnd.google-earth.kml+xml",
+            TrackFormat::Csv => "text/csv",
+        }
+    }
+
+    /// file extension used in the suggested file name
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "gpx",
+            TrackFormat::GeoJson => "geojson",
+            TrackFormat::Kml => "kml",
+            TrackFormat::Csv => "csv",
+        }
+    }
+
+    /// picks the first known format from the value of an accept header
+    pub fn from_accept(accept: &str) -> Option<TrackFormat> {
+        accept
+            .split(',')
+            .map(|entry| entry.split(';').next().unwrap_or("").trim())
+            .find_map(|mime| match mime {
+                "application/gpx+xml" => Some(TrackFormat::Gpx),
+                "application/geo+json" | "application/json" => Some(TrackFormat::GeoJson),
+                "application/vnd.google-earth.kml+xml" => Some(TrackFormat::Kml),
+                "text/csv" => Some(TrackFormat::Csv),
+                _ => None,
+            })
+    }
+
+    /// serializes the points of the given run into this format
+    pub fn write(&self, trekkie_run: &TrekkieRun, points: &[GpsPoint]) -> String {
+        match self {
+            TrackFormat::Gpx => gpx::write(trekkie_run, points),
+            TrackFormat::GeoJson => geojson::write(trekkie_run, points),
+            TrackFormat::Kml => kml::write(trekkie_run, points),
+            TrackFormat::Csv => csv::write(points),
+        }
+    }
+}
+
+/// File formats a gps track can be imported from
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Gpx,
+    Fit,
+    Tcx,
+    Kml,
+    GeoJson,
+    Nmea,
+    Csv,
+}
+
+impl ImportFormat {
+    /// Detects the format of an uploaded file. FIT files are recognized by their magic bytes,
+    /// otherwise a specific content type wins over the file extension and the beginning of the
+    /// file.
+    pub fn detect(
+        content_type: Option<&str>,
+        file_name: Option<&str>,
+        head: &[u8],
+    ) -> Option<ImportFormat> {
+        if head.len() >= 12 && &head[8..12] == b".FIT" {
+            return Some(ImportFormat::Fit);
+        }
+
+        content_type
+            .and_then(ImportFormat::from_content_type)
+            .or_else(|| file_name.and_then(ImportFormat::from_file_name))
+            .or_else(|| ImportFormat::sniff(head))
+    }
+
+    fn from_content_type(content_type: &str) -> Option<ImportFormat> {
+        match content_type.split(';').next().unwrap_or("").trim() {
+            "application/gpx+xml" => Some(ImportFormat::Gpx),
+            "application/vnd.ant.fit" | "application/fit" => Some(ImportFormat::Fit),
+            "application/vnd.garmin.tcx+xml" => Some(ImportFormat::Tcx),
+            "application/vnd.google-earth.kml+xml" => Some(ImportFormat::Kml),
+            "application/geo+json" => Some(ImportFormat::GeoJson),
+            "text/csv" => Some(ImportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    fn from_file_name(file_name: &str) -> Option<ImportFormat> {
+        let extension = file_name.rsplit_once('.')?.1.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "gpx" => Some(ImportFormat::Gpx),
+            "fit" => Some(ImportFormat::Fit),
+            "tcx" => Some(ImportFormat::Tcx),
+            "kml" => Some(ImportFormat::Kml),
+            "geojson" | "json" => Some(ImportFormat::GeoJson),
+            "nmea" | "nma" => Some(ImportFormat::Nmea),
+            "csv" => Some(ImportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    /// guesses the format from the first bytes of the file
+    fn sniff(head: &[u8]) -> Option<ImportFormat> {
+        let text = String::from_utf8_lossy(head);
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+
+        if text.starts_with('$') || text.starts_with('!') {
+            Some(ImportFormat::Nmea)
+        } else if text.starts_with('{') {
+            Some(ImportFormat::GeoJson)
+        } else if text.starts_with('<') {
+            if text.contains("<gpx") {
+                Some(ImportFormat::Gpx)
+            } else if text.contains("<TrainingCenterDatabase") {
+                Some(ImportFormat::Tcx)
+            } else if text.contains("<kml") {
+                Some(ImportFormat::Kml)
+            } else {
+                None
+            }
+        } else if text.lines().next().is_some_and(csv::is_header) {
+            Some(ImportFormat::Csv)
+        } else {
+            None
+        }
+    }
+
+    /// reads the points of a file in this format and passes them to the sink one by one
+    pub fn read<R: BufRead, S: TrackSink>(
+        &self,
+        reader: R,
+        run_id: Uuid,
+        sink: &mut S,
+    ) -> Result<(), String> {
+        match self {
+            ImportFormat::Gpx => gpx::read(reader, run_id, sink),
+            ImportFormat::Fit => fit::read(reader, run_id, sink),
+            ImportFormat::Tcx => tcx::read(reader, run_id, sink),
+            ImportFormat::Kml => kml::read(reader, run_id, sink),
+            ImportFormat::GeoJson => geojson::read(reader, run_id, sink),
+            ImportFormat::Nmea => nmea::read(reader, run_id, sink),
+            ImportFormat::Csv => csv::read(reader, run_id, sink),
+        }
+    }
+}
+
+/// Name and content type of an uploaded file, used to detect its format
+#[derive(Debug, Default)]
+pub struct UploadedFile {
+    pub content_type: Option<String>,
+    pub file_name: Option<String>,
+}
+
+/// Reads an uploaded track file, which may be gzip or zstd compressed, into the sink. The format
+/// is detected if it is not given and the bytes read after decompression are taken from the
+/// budget of the whole upload.
+pub fn import<R: Read, S: TrackSink>(
+    reader: R,
+    file: &UploadedFile,
+    format: Option<ImportFormat>,
+    budget: &mut SizeBudget,
+    run_id: Uuid,
+    sink: &mut S,
+) -> Result<ImportFormat, String> {
+    let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, reader);
+    let head = reader
+        .fill_buf()
+        .map_err(|e| format!("cannot read file: {}", e))?;
+
+    match Compression::detect(head) {
+        Some(compression) => {
+            let decoder = compression
+                .decoder(reader)
+                .map_err(|e| format!("cannot decompress {:?} file: {}", compression, e))?;
+            let reader =
+                BufReader::with_capacity(READ_BUFFER_SIZE, LimitedReader::new(decoder, budget));
+            let file_name = file.file_name.as_deref().map(strip_extension);
+
+            read_detected(reader, None, file_name, format, run_id, sink)
+        }
+        None => {
+            let reader =
+                BufReader::with_capacity(READ_BUFFER_SIZE, LimitedReader::new(reader, budget));
+
+            read_detected(
+                reader,
+                file.content_type.as_deref(),
+                file.file_name.as_deref(),
+                format,
+                run_id,
+                sink,
+            )
+        }
+    }
+}
+
+fn read_detected<R: BufRead, S: TrackSink>(
+    mut reader: R,
+    content_type: Option<&str>,
+    file_name: Option<&str>,
+    format: Option<ImportFormat>,
+    run_id: Uuid,
+    sink: &mut S,
+) -> Result<ImportFormat, String> {
+    let head = reader
+        .fill_buf()
+        .map_err(|e| format!("cannot read file: {}", e))?;
+    let format = format
+        .or_else(|| ImportFormat::detect(content_type, file_name, head))
+        .ok_or_else(|| {
+            format!(
+                "cannot detect format of {}",
+                file_name.unwrap_or("uploaded file")
+            )
+        })?;
+
+    format
+        .read(reader, run_id, sink)
+        .map_err(|e| format!("file is not valid {:?}: {}", format, e))?;
+
+    Ok(format)
+}
+
+/// Receives the points of an uploaded file while it is read
+pub trait TrackSink {
+    /// adds the next point of the file
+    fn push(&mut self, point: InsertGpsPoint);
+
+    /// records that the next point of the file cannot be used
+    fn skip(&mut self, reason: String);
+}
+
+/// Values of a point collected while reading an uploaded file
+#[derive(Default)]
+pub struct PointBuilder {
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub elevation: Option<f64>,
+    pub time: Option<String>,
+    pub speed: Option<f64>,
+    pub bearing: Option<f64>,
+    pub accuracy: Option<f64>,
+    pub vertical_accuracy: Option<f64>,
+}
+
+impl PointBuilder {
+    /// adds the point to the track or records why it has to be skipped
+    pub fn finish<S: TrackSink>(self, run_id: Uuid, sink: &mut S) {
+        let (lat, lon) = match (self.lat, self.lon) {
+            (Some(lat), Some(lon)) if valid_coordinates(lat, lon) => (lat, lon),
+            _ => {
+                sink.skip("missing or invalid coordinates".to_string());
+                return;
+            }
+        };
+
+        let timestamp = match self.time.as_deref().map(|text| (text, parse_time(text))) {
+            Some((_, Some(timestamp))) => timestamp,
+            Some((text, None)) => {
+                sink.skip(format!("cannot parse time {}", text));
+                return;
+            }
+            None => {
+                sink.skip("point has no time".to_string());
+                return;
+            }
+        };
+
+        sink.push(InsertGpsPoint {
+            id: None,
+            trekkie_run: run_id,
+            timestamp,
+            lat,
+            lon,
+            elevation: self.elevation,
+            accuracy: self.accuracy,
+            vertical_accuracy: self.vertical_accuracy,
+            bearing: self.bearing,
+            speed: self.speed,
+        });
+    }
+}
+
+/// checks that latitude and longitude are inside the wgs84 range
+pub fn valid_coordinates(lat: f64, lon: f64) -> bool {
+    (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)
+}
+
+/// parses a number of an uploaded file, empty values are treated as unknown
+pub fn parse_number(text: &str) -> Option<f64> {
+    text.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite())
+}
+
+/// Parses a timestamp of an uploaded file. Accepts RFC 3339 with and without fractional seconds
+/// or offset and unix timestamps in seconds or milliseconds, timestamps without offset are
+/// treated as utc.
+pub fn parse_time(text: &str) -> Option<NaiveDateTime> {
+    let text = text.trim();
+
+    if let Some(epoch) = parse_number(text) {
+        // everything after 5138 in seconds is taken as milliseconds
+        let millis = if epoch.abs() < 1e11 {
+            epoch * 1000.0
+        } else {
+            epoch
+        };
+        return DateTime::from_timestamp_millis(millis as i64).map(|time| time.naive_utc());
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.naive_utc());
+    }
+
+    // offsets without colon like +0200
+    if let Ok(time) = DateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f%z") {
+        return Some(time.naive_utc());
+    }
+
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+}
+
+/// formats a timestamp from the database as RFC 3339 in UTC
+pub fn format_time(time: &NaiveDateTime) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// human readable name of a run used as track name
+pub fn track_name(trekkie_run: &TrekkieRun) -> String {
+    format!(
+        "line {} run {} ({})",
+        trekkie_run.line,
+        trekkie_run.run,
+        format_time(&trekkie_run.start_time)
+    )
+}
+
+/// escapes the characters which are not allowed in xml text nodes and attributes
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    /// collects the points and skip reasons of a parser
+    #[derive(Default)]
+    pub(crate) struct CollectingSink {
+        pub points: Vec<InsertGpsPoint>,
+        pub skipped: Vec<String>,
+    }
+
+    impl TrackSink for CollectingSink {
+        fn push(&mut self, point: InsertGpsPoint) {
+            self.points.push(point);
+        }
+
+        fn skip(&mut self, reason: String) {
+            self.skipped.push(reason);
+        }
+    }
+
+    pub(crate) fn time(hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, second)
+            .unwrap()
+    }
+
+    pub(crate) fn assert_close(value: f64, expected: f64) {
+        assert!(
+            (value - expected).abs() < 1e-6,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_time_epoch() {
+        assert_eq!(parse_time("1714564800"), Some(time(12, 0, 0)));
+        assert_eq!(
+            parse_time("1714564800.5"),
+            Some(time(12, 0, 0) + chrono::Duration::milliseconds(500))
+        );
+        assert_eq!(parse_time("1714564800000"), Some(time(12, 0, 0)));
+        assert_eq!(parse_time(" 1714564801000 "), Some(time(12, 0, 1)));
+    }
+
+    #[test]
+    fn parse_time_text() {
+        assert_eq!(parse_time("2024-05-01T12:00:00Z"), Some(time(12, 0, 0)));
+        assert_eq!(
+            parse_time("2024-05-01T14:00:00+02:00"),
+            Some(time(12, 0, 0))
+        );
+        assert_eq!(
+            parse_time("2024-05-01T14:00:00.000+0200"),
+            Some(time(12, 0, 0))
+        );
+        assert_eq!(parse_time("2024-05-01T12:00:00"), Some(time(12, 0, 0)));
+        assert_eq!(parse_time("2024-05-01 12:00:00"), Some(time(12, 0, 0)));
+        assert_eq!(parse_time("yesterday"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn point_builder_skips() {
+        let run_id = Uuid::nil();
+        let mut sink = CollectingSink::default();
+
+        PointBuilder {
+            lat: Some(91.0),
+            lon: Some(13.0),
+            time: Some("2024-05-01T12:00:00Z".to_string()),
+            ..Default::default()
+        }
+        .finish(run_id, &mut sink);
+        PointBuilder {
+            lat: Some(51.0),
+            lon: Some(13.0),
+            time: Some("noon".to_string()),
+            ..Default::default()
+        }
+        .finish(run_id, &mut sink);
+        PointBuilder {
+            lat: Some(51.0),
+            lon: Some(13.0),
+            ..Default::default()
+        }
+        .finish(run_id, &mut sink);
+
+        assert!(sink.points.is_empty());
+        assert_eq!(
+            sink.skipped,
+            [
+                "missing or invalid coordinates",
+                "cannot parse time noon",
+                "point has no time"
+            ]
+        );
+    }
+}
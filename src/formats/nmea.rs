@@ -0,0 +1,195 @@
+use crate::formats::{parse_number, valid_coordinates, TrackSink};
+
+use tlms::locations::gps::InsertGpsPoint;
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use uuid::Uuid;
+
+use std::io::BufRead;
+
+/// meters per second in one knot
+const KNOTS: f64 = 0.514444;
+
+/// compares the xor of all characters between $ and * with the transmitted checksum
+fn checksum_matches(body: &str, checksum: &str) -> bool {
+    let expected = body.bytes().fold(0u8, |checksum, byte| checksum ^ byte);
+    u8::from_str_radix(checksum.trim(), 16) == Ok(expected)
+}
+
+/// converts a ddmm.mmmm or dddmm.mmmm value with hemisphere into degrees
+fn degrees(value: &str, hemisphere: &str) -> Option<f64> {
+    let value = parse_number(value)?;
+    let whole_degrees = (value / 100.0).trunc();
+    let degrees = whole_degrees + (value - whole_degrees * 100.0) / 60.0;
+
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+/// builds a point from the fields of a RMC sentence, the elevation is taken from the GGA
+/// sentence of the same fix
+fn recommended_minimum(
+    fields: &[&str],
+    altitude: Option<f64>,
+    run_id: Uuid,
+) -> Result<InsertGpsPoint, String> {
+    let field = |index: usize| fields.get(index).copied().unwrap_or("");
+
+    if field(2) != "A" {
+        return Err("receiver has no fix".to_string());
+    }
+
+    let time = NaiveTime::parse_from_str(field(1), "%H%M%S%.f")
+        .map_err(|_| format!("cannot parse time {}", field(1)))?;
+    let date = NaiveDate::parse_from_str(field(9), "%d%m%y")
+        .map_err(|_| format!("cannot parse date {}", field(9)))?;
+
+    let (lat, lon) = match (degrees(field(3), field(4)), degrees(field(5), field(6))) {
+        (Some(lat), Some(lon)) if valid_coordinates(lat, lon) => (lat, lon),
+        _ => return Err("missing or invalid coordinates".to_string()),
+    };
+
+    Ok(InsertGpsPoint {
+        id: None,
+        trekkie_run: run_id,
+        timestamp: NaiveDateTime::new(date, time),
+        lat,
+        lon,
+        elevation: altitude,
+        accuracy: None,
+        vertical_accuracy: None,
+        bearing: parse_number(field(8)),
+        speed: parse_number(field(7)).map(|knots| knots * KNOTS),
+    })
+}
+
+/// Reads a NMEA 0183 log. Every RMC sentence is one point, because only those carry the date,
+/// the altitude is merged in from the GGA sentence with the same time. All other sentences are
+/// ignored.
+pub fn read<R: BufRead, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    // time and altitude of the last GGA sentence
+    let mut last_fix: Option<(String, Option<f64>)> = None;
+    // point of the last RMC sentence, held back until the GGA sentence of the same fix was seen
+    let mut pending: Option<(String, InsertGpsPoint)> = None;
+
+    for line in reader.lines() {
+        let line = line.map_err(|e| format!("cannot read nmea log: {}", e))?;
+        let sentence = match line.trim().strip_prefix('$') {
+            Some(sentence) => sentence,
+            None => continue,
+        };
+
+        let (body, valid) = match sentence.split_once('*') {
+            Some((body, checksum)) => (body, checksum_matches(body, checksum)),
+            None => (sentence, true),
+        };
+        let fields: Vec<&str> = body.split(',').collect();
+        let time = fields.get(1).copied().unwrap_or("");
+
+        // the sentence type follows the two characters of the talker id like GP or GN
+        match fields[0].get(2..) {
+            Some("RMC") => {
+                if let Some((_, point)) = pending.take() {
+                    sink.push(point);
+                }
+
+                if !valid {
+                    sink.skip("checksum mismatch".to_string());
+                    continue;
+                }
+
+                let altitude = last_fix
+                    .as_ref()
+                    .filter(|(fix_time, _)| fix_time == time)
+                    .and_then(|(_, altitude)| *altitude);
+
+                match recommended_minimum(&fields, altitude, run_id) {
+                    Ok(point) => pending = Some((time.to_string(), point)),
+                    Err(reason) => sink.skip(reason),
+                }
+            }
+            Some("GGA") if valid => {
+                let altitude = fields.get(9).and_then(|altitude| parse_number(altitude));
+
+                // the GGA sentence may also follow the RMC sentence of its fix
+                if let Some((pending_time, mut point)) = pending.take() {
+                    if pending_time == time {
+                        point.elevation = point.elevation.or(altitude);
+                    }
+                    sink.push(point);
+                }
+
+                last_fix = Some((time.to_string(), altitude));
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((_, point)) = pending {
+        sink.push(point);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::tests::{assert_close, CollectingSink};
+
+    use chrono::NaiveDate;
+
+    const LOG: &str = "\
+$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47
+$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A
+$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39
+$GPRMC,123520,V,,,,,,,230394,,*39
+$GPRMC,123521,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*00
+";
+
+    #[test]
+    fn read_log() {
+        let mut sink = CollectingSink::default();
+        read(LOG.as_bytes(), Uuid::nil(), &mut sink).unwrap();
+
+        assert_eq!(sink.points.len(), 1);
+        let point = &sink.points[0];
+        assert_eq!(
+            point.timestamp,
+            NaiveDate::from_ymd_opt(1994, 3, 23)
+                .unwrap()
+                .and_hms_opt(12, 35, 19)
+                .unwrap()
+        );
+        assert_close(point.lat, 48.0 + 7.038 / 60.0);
+        assert_close(point.lon, 11.0 + 31.0 / 60.0);
+        assert_eq!(point.elevation, Some(545.4));
+        assert_close(point.speed.unwrap(), 22.4 * KNOTS);
+        assert_eq!(point.bearing, Some(84.4));
+
+        assert_eq!(sink.skipped, ["receiver has no fix", "checksum mismatch"]);
+    }
+
+    #[test]
+    fn altitude_after_rmc() {
+        let log = "\
+$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A
+$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47
+";
+        let mut sink = CollectingSink::default();
+        read(log.as_bytes(), Uuid::nil(), &mut sink).unwrap();
+
+        assert_eq!(sink.points.len(), 1);
+        assert_eq!(sink.points[0].elevation, Some(545.4));
+    }
+
+    #[test]
+    fn hemispheres() {
+        assert_close(degrees("4807.038", "S").unwrap(), -(48.0 + 7.038 / 60.0));
+        assert_close(degrees("01131.000", "W").unwrap(), -(11.0 + 31.0 / 60.0));
+        assert_eq!(degrees("4807.038", ""), None);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::formats::{parse_number, PointBuilder, TrackSink};
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use uuid::Uuid;
+
+use std::io::BufRead;
+
+/// stores the text of a child element of a track point
+fn set_value(point: &mut PointBuilder, element: &[u8], text: &str) {
+    match element {
+        b"Time" => point.time = Some(text.to_string()),
+        b"LatitudeDegrees" => point.lat = parse_number(text),
+        b"LongitudeDegrees" => point.lon = parse_number(text),
+        b"AltitudeMeters" => point.elevation = parse_number(text),
+        // ActivityExtension TPX speed in meters per second
+        b"Speed" => point.speed = parse_number(text),
+        _ => {}
+    }
+}
+
+/// Reads the track points of a Garmin Training Center document from activities and courses.
+/// Track points without position, which garmin devices write before they have a fix, are
+/// reported as skipped.
+pub fn read<R: BufRead, S: TrackSink>(reader: R, run_id: Uuid, sink: &mut S) -> Result<(), String> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut buffer = Vec::new();
+    let mut elements: Vec<Vec<u8>> = Vec::new();
+    let mut pending: Option<PointBuilder> = None;
+    let mut is_tcx = false;
+
+    loop {
+        match reader.read_event_into(&mut buffer) {
+            Ok(Event::Start(element)) => {
+                let name = element.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"TrainingCenterDatabase" => is_tcx = true,
+                    b"Trackpoint" => pending = Some(PointBuilder::default()),
+                    _ => {}
+                }
+                elements.push(name);
+            }
+            Ok(Event::Empty(element)) => {
+                if element.local_name().as_ref() == b"Trackpoint" {
+                    PointBuilder::default().finish(run_id, sink);
+                }
+            }
+            Ok(Event::Text(text)) => {
+                if let (Some(point), Some(element)) = (pending.as_mut(), elements.last()) {
+                    let text = text.unescape().map_err(|e| {
+                        format!("invalid text at {}: {}", reader.buffer_position(), e)
+                    })?;
+                    set_value(point, element, &text);
+                }
+            }
+            Ok(Event::End(element)) => {
+                if element.local_name().as_ref() == b"Trackpoint" {
+                    if let Some(point) = pending.take() {
+                        point.finish(run_id, sink);
+                    }
+                }
+                elements.pop();
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(format!(
+                    "malformed xml at {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+        }
+        buffer.clear();
+    }
+
+    if !is_tcx {
+        return Err("document has no TrainingCenterDatabase element".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::tests::{assert_close, time, CollectingSink};
+
+    #[test]
+    fn read_activity() {
+        let document = r#"<?xml version="1.0" encoding="UTF-8"?>
+<TrainingCenterDatabase xmlns="http://www.garmin.com/xmlschemas/TrainingCenterDatabase/v2">
+  <Activities><Activity Sport="Other"><Lap StartTime="2024-05-01T12:00:00Z"><Track>
+    <Trackpoint><Time>2024-05-01T11:59:59Z</Time></Trackpoint>
+    <Trackpoint>
+      <Time>2024-05-01T12:00:00Z</Time>
+      <Position><LatitudeDegrees>51.05</LatitudeDegrees><LongitudeDegrees>13.74</LongitudeDegrees></Position>
+      <AltitudeMeters>112.5</AltitudeMeters>
+      <Extensions><TPX xmlns="http://www.garmin.com/xmlschemas/ActivityExtension/v2"><Speed>4.2</Speed></TPX></Extensions>
+    </Trackpoint>
+    <Trackpoint/>
+  </Track></Lap></Activity></Activities>
+</TrainingCenterDatabase>"#;
+
+        let mut sink = CollectingSink::default();
+        read(document.as_bytes(), Uuid::nil(), &mut sink).unwrap();
+
+        assert_eq!(sink.points.len(), 1);
+        assert_eq!(sink.points[0].timestamp, time(12, 0, 0));
+        assert_close(sink.points[0].lat, 51.05);
+        assert_close(sink.points[0].lon, 13.74);
+        assert_eq!(sink.points[0].elevation, Some(112.5));
+        assert_eq!(sink.points[0].speed, Some(4.2));
+        assert_eq!(
+            sink.skipped,
+            [
+                "missing or invalid coordinates",
+                "missing or invalid coordinates"
+            ]
+        );
+    }
+
+    #[test]
+    fn other_document() {
+        let mut sink = CollectingSink::default();
+        let result = read("<gpx></gpx>".as_bytes(), Uuid::nil(), &mut sink);
+
+        assert_eq!(
+            result,
+            Err("document has no TrainingCenterDatabase element".to_string())
+        );
+    }
+}
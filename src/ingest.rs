@@ -0,0 +1,426 @@
+use crate::config::Geofence;
+use crate::formats::TrackSink;
+use crate::lifecycle::{lock_state, RunState};
+use crate::routes::ServerError;
+use crate::structs::Args;
+use crate::validation::{Validation, ValidationConfig};
+
+use tlms::locations::gps::InsertGpsPoint;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use std::collections::HashSet;
+
+/// maximum amount of rows per insert statement, keeps the insert below the postgres parameter
+/// limit
+const INSERT_CHUNK_SIZE: usize = 5000;
+
+/// time and position of a stored gps point
+pub type StoredPoint = (NaiveDateTime, f64, f64);
+
+/// Limits of the file uploads and the validation of submitted points
+#[derive(Debug, Clone)]
+pub struct IngestConfig {
+    /// maximum size of all files of one upload in bytes
+    pub max_upload_size: u64,
+    /// validation of live, batch and websocket points
+    pub validation: ValidationConfig,
+    /// validation of uploaded and imported track files
+    pub file_validation: ValidationConfig,
+}
+
+impl IngestConfig {
+    pub fn from_args(args: &Args) -> IngestConfig {
+        let validation = ValidationConfig::from_args(args);
+        IngestConfig {
+            max_upload_size: args.max_upload_size,
+            file_validation: validation.without_age_limit(),
+            validation,
+        }
+    }
+}
+
+/// Reference to a submitted point by its position in the request and the optional client
+/// sequence number
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct PointRef {
+    pub index: usize,
+    pub sequence: Option<u64>,
+}
+
+/// Point of an uploaded file which could not be read
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct SkippedPoint {
+    /// position of the point in the file
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Point which failed the validation
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct InvalidPoint {
+    pub index: usize,
+    pub sequence: Option<u64>,
+    /// flagged points are stored anyway, rejected ones are not
+    pub stored: bool,
+    pub problems: Vec<String>,
+}
+
+impl InvalidPoint {
+    /// report entry of a point which did not pass the validation
+    pub fn new(point_ref: PointRef, validation: Validation) -> Option<InvalidPoint> {
+        let (stored, problems) = match validation {
+            Validation::Valid => return None,
+            Validation::Flagged(problems) => (true, problems),
+            Validation::Rejected(problems) => (false, problems),
+        };
+
+        Some(InvalidPoint {
+            index: point_ref.index,
+            sequence: point_ref.sequence,
+            stored,
+            problems,
+        })
+    }
+}
+
+/// Tells the client which of the submitted points were stored
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default)]
+pub struct IngestReport {
+    /// amount of points which were newly stored
+    pub inserted: usize,
+    /// points which were already stored and have been skipped
+    pub duplicates: Vec<PointRef>,
+    /// points of an uploaded file which could not be read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<SkippedPoint>,
+    /// points which failed the validation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invalid: Vec<InvalidPoint>,
+}
+
+impl IngestReport {
+    /// adds the result of another call of [`insert_gps_points`] to the report
+    pub fn add(&mut self, is_new: &[bool], refs: impl IntoIterator<Item = PointRef>) {
+        for (fresh, point_ref) in is_new.iter().zip(refs) {
+            if *fresh {
+                self.inserted += 1;
+            } else {
+                self.duplicates.push(point_ref);
+            }
+        }
+    }
+}
+
+/// Validates the submitted points and stores the valid and flagged ones. Returns the report
+/// together with time and position of the newly stored points.
+pub fn ingest_points(
+    run_id: Uuid,
+    submitted: Vec<(InsertGpsPoint, PointRef)>,
+    validation: &ValidationConfig,
+    geofence: Option<&Geofence>,
+    database_connection: &mut PgConnection,
+) -> Result<(IngestReport, Vec<StoredPoint>), ServerError> {
+    let now = Utc::now().naive_utc();
+    let mut report = IngestReport::default();
+    let mut points = Vec::with_capacity(submitted.len());
+    let mut refs = Vec::with_capacity(submitted.len());
+
+    for (point, point_ref) in submitted {
+        let validation = validation.check(&point, geofence, now);
+        let rejected = matches!(validation, Validation::Rejected(_));
+
+        if let Some(invalid) = InvalidPoint::new(point_ref.clone(), validation) {
+            report.invalid.push(invalid);
+        }
+        if !rejected {
+            points.push(point);
+            refs.push(point_ref);
+        }
+    }
+
+    let keys: Vec<StoredPoint> = points
+        .iter()
+        .map(|point| (point.timestamp, point.lat, point.lon))
+        .collect();
+    // live points are only taken while the run is recording
+    let is_new = insert_gps_points(run_id, points, false, database_connection)?;
+
+    report.add(&is_new, refs);
+    let stored = keys
+        .into_iter()
+        .zip(is_new.iter())
+        .filter(|(_, fresh)| **fresh)
+        .map(|(key, _)| key)
+        .collect();
+
+    Ok((report, stored))
+}
+
+/// Stores the points of an uploaded file in chunks while the file is read, so a long track is
+/// never held in memory as a whole.
+pub struct ChunkedInsert<'a> {
+    run_id: Uuid,
+    /// if the points may be added to a finished run
+    accept_finished: bool,
+    database_connection: &'a mut PgConnection,
+    validation: &'a ValidationConfig,
+    /// geofence of the run's region
+    geofence: Option<&'a Geofence>,
+    now: NaiveDateTime,
+    points: Vec<InsertGpsPoint>,
+    indices: Vec<usize>,
+    /// amount of points seen in the uploaded files so far
+    total: usize,
+    report: IngestReport,
+    /// time and position of the newly stored points if they should be forwarded
+    stored: Option<Vec<StoredPoint>>,
+    error: Option<ServerError>,
+}
+
+impl<'a> ChunkedInsert<'a> {
+    /// keep_stored remembers time and position of every newly stored point, accept_finished
+    /// allows to add the points to a finished run like the v1 runs
+    pub fn new(
+        run_id: Uuid,
+        accept_finished: bool,
+        database_connection: &'a mut PgConnection,
+        validation: &'a ValidationConfig,
+        geofence: Option<&'a Geofence>,
+        keep_stored: bool,
+    ) -> ChunkedInsert<'a> {
+        ChunkedInsert {
+            run_id,
+            accept_finished,
+            database_connection,
+            validation,
+            geofence,
+            now: Utc::now().naive_utc(),
+            points: Vec::new(),
+            indices: Vec::new(),
+            total: 0,
+            report: IngestReport::default(),
+            stored: keep_stored.then(Vec::new),
+            error: None,
+        }
+    }
+
+    fn flush(&mut self) {
+        let points = std::mem::take(&mut self.points);
+        let indices = std::mem::take(&mut self.indices);
+
+        // after the first failed insert the remaining points are only counted
+        if points.is_empty() || self.error.is_some() {
+            return;
+        }
+
+        let keys: Option<Vec<StoredPoint>> = self.stored.as_ref().map(|_| {
+            points
+                .iter()
+                .map(|point| (point.timestamp, point.lat, point.lon))
+                .collect()
+        });
+
+        match insert_gps_points(
+            self.run_id,
+            points,
+            self.accept_finished,
+            self.database_connection,
+        ) {
+            Ok(is_new) => {
+                self.report.add(
+                    &is_new,
+                    indices.into_iter().map(|index| PointRef {
+                        index,
+                        sequence: None,
+                    }),
+                );
+
+                if let (Some(stored), Some(keys)) = (self.stored.as_mut(), keys) {
+                    stored.extend(
+                        keys.into_iter()
+                            .zip(is_new.iter())
+                            .filter(|(_, fresh)| **fresh)
+                            .map(|(key, _)| key),
+                    );
+                }
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// stores the remaining points and returns the report together with the newly stored points
+    pub fn finish(mut self) -> Result<(IngestReport, Vec<StoredPoint>), ServerError> {
+        self.flush();
+
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok((self.report, self.stored.unwrap_or_default())),
+        }
+    }
+}
+
+impl TrackSink for ChunkedInsert<'_> {
+    fn push(&mut self, point: InsertGpsPoint) {
+        let index = self.total;
+        self.total += 1;
+
+        let validation = self.validation.check(&point, self.geofence, self.now);
+        let rejected = matches!(validation, Validation::Rejected(_));
+        let point_ref = PointRef {
+            index,
+            sequence: None,
+        };
+        if let Some(invalid) = InvalidPoint::new(point_ref, validation) {
+            self.report.invalid.push(invalid);
+        }
+        if rejected {
+            return;
+        }
+
+        self.points.push(point);
+        self.indices.push(index);
+
+        if self.points.len() >= INSERT_CHUNK_SIZE {
+            self.flush();
+        }
+    }
+
+    fn skip(&mut self, reason: String) {
+        self.report.skipped.push(SkippedPoint {
+            index: self.total,
+            reason,
+        });
+        self.total += 1;
+    }
+}
+
+/// key which identifies a gps point inside a run
+fn point_key(time: NaiveDateTime, lat: f64, lon: f64) -> (NaiveDateTime, u64, u64) {
+    (time, lat.to_bits(), lon.to_bits())
+}
+
+/// Inserts the gps points of a run and skips every point whose timestamp and position is already
+/// stored for this run or occurs earlier in the same list. The run row is locked for the
+/// duration, so retries running in parallel cannot both insert. Fails with a conflict if the
+/// run is aborted, empty or, unless accept_finished is set, finished. Returns for every point if
+/// it was newly stored.
+pub fn insert_gps_points(
+    run_id: Uuid,
+    points: Vec<InsertGpsPoint>,
+    accept_finished: bool,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<bool>, ServerError> {
+    let (first, last) = match (
+        points.iter().map(|point| point.timestamp).min(),
+        points.iter().map(|point| point.timestamp).max(),
+    ) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut failure: Option<ServerError> = None;
+
+    let result = database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        use tlms::schema::gps_points::dsl::gps_points;
+        use tlms::schema::gps_points::{lat, lon, timestamp, trekkie_run};
+
+        let state = match lock_state(run_id, conn) {
+            Ok(state) => state,
+            Err(e) => {
+                failure = Some(e);
+                return Err(diesel::result::Error::RollbackTransaction);
+            }
+        };
+
+        // v1 runs are created finished and receive their points afterwards
+        let accepted = match state {
+            RunState::Finished => accept_finished,
+            state => !state.is_terminal(),
+        };
+        if !accepted {
+            failure = Some(ServerError::Conflict);
+            return Err(diesel::result::Error::RollbackTransaction);
+        }
+        let run_finished = state == RunState::Finished;
+
+        let mut known: HashSet<(NaiveDateTime, u64, u64)> = gps_points
+            .filter(trekkie_run.eq(run_id))
+            .filter(timestamp.between(first, last))
+            .select((timestamp, lat, lon))
+            .load::<(NaiveDateTime, f64, f64)>(conn)?
+            .into_iter()
+            .map(|(time, point_lat, point_lon)| point_key(time, point_lat, point_lon))
+            .collect();
+
+        let is_new: Vec<bool> = points
+            .iter()
+            .map(|point| known.insert(point_key(point.timestamp, point.lat, point.lon)))
+            .collect();
+
+        let new_points: Vec<InsertGpsPoint> = points
+            .into_iter()
+            .zip(is_new.iter())
+            .filter(|(_, fresh)| **fresh)
+            .map(|(point, _)| point)
+            .collect();
+
+        for chunk in new_points.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(gps_points)
+                .values(chunk)
+                .execute(conn)?;
+        }
+
+        // keeps the reaper from finishing runs which still receive points, the first point
+        // moves a created run to recording
+        if !new_points.is_empty() {
+            use crate::schema::trekkie_run_activity::dsl as activity;
+
+            let now = Utc::now().naive_utc();
+            diesel::insert_into(activity::trekkie_run_activity)
+                .values((
+                    activity::trekkie_run.eq(run_id),
+                    activity::created_at.eq(now),
+                    activity::last_point_at.eq(now),
+                    activity::state.eq(if run_finished {
+                        RunState::Finished.as_str()
+                    } else {
+                        RunState::Recording.as_str()
+                    }),
+                ))
+                .on_conflict(activity::trekkie_run)
+                .do_update()
+                .set(activity::last_point_at.eq(now))
+                .execute(conn)?;
+            if !run_finished {
+                diesel::update(activity::trekkie_run_activity)
+                    .filter(activity::trekkie_run.eq(run_id))
+                    .filter(activity::state.eq(RunState::Created.as_str()))
+                    .set(activity::state.eq(RunState::Recording.as_str()))
+                    .execute(conn)?;
+            } else {
+                // the stored statistics of a finished run do not cover the new points anymore
+                use crate::schema::trekkie_run_stats::dsl as stats;
+
+                diesel::delete(stats::trekkie_run_stats.filter(stats::trekkie_run.eq(run_id)))
+                    .execute(conn)?;
+            }
+        }
+
+        Ok(is_new)
+    });
+
+    match (result, failure) {
+        (Ok(is_new), _) => Ok(is_new),
+        (Err(_), Some(e)) => Err(e),
+        (Err(e), None) => {
+            error!("while trying to insert gps points {:?}", e);
+            Err(ServerError::InternalError)
+        }
+    }
+}
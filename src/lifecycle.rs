@@ -0,0 +1,141 @@
+use crate::models::RunActivity;
+use crate::routes::ServerError;
+
+use tlms::trekkie::TrekkieRun;
+
+use chrono::Utc;
+use diesel::{ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// State of a run. A run is created without points, starts recording with its first point and
+/// ends up finished, aborted by the app or empty if it was terminated without any point.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState {
+    Created,
+    Recording,
+    Finished,
+    Aborted,
+    Empty,
+}
+
+impl RunState {
+    /// name of the state as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunState::Created => "created",
+            RunState::Recording => "recording",
+            RunState::Finished => "finished",
+            RunState::Aborted => "aborted",
+            RunState::Empty => "empty",
+        }
+    }
+
+    fn parse(name: &str) -> Option<RunState> {
+        match name {
+            "created" => Some(RunState::Created),
+            "recording" => Some(RunState::Recording),
+            "finished" => Some(RunState::Finished),
+            "aborted" => Some(RunState::Aborted),
+            "empty" => Some(RunState::Empty),
+            _ => None,
+        }
+    }
+
+    /// finished, aborted and empty runs take no more points and cannot be terminated again
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            RunState::Finished | RunState::Aborted | RunState::Empty
+        )
+    }
+}
+
+/// State of the run together with the reason it was aborted with. Runs without a stored state,
+/// like v1 submissions, are finished or created depending on their finished flag.
+pub fn run_state(
+    trekkie_run: &TrekkieRun,
+    database_connection: &mut PgConnection,
+) -> Result<(RunState, Option<String>), ServerError> {
+    use crate::schema::trekkie_run_activity::dsl as activity;
+
+    let stored = activity::trekkie_run_activity
+        .filter(activity::trekkie_run.eq(trekkie_run.id))
+        .first::<RunActivity>(database_connection)
+        .optional()
+        .map_err(|e| {
+            error!("database error while loading run state {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    match stored {
+        Some(activity) => match RunState::parse(&activity.state) {
+            Some(state) => Ok((state, activity.aborted_reason)),
+            None => {
+                error!(
+                    "run {} has unknown state {}",
+                    activity.trekkie_run, activity.state
+                );
+                Err(ServerError::InternalError)
+            }
+        },
+        None if trekkie_run.finished => Ok((RunState::Finished, None)),
+        None => Ok((RunState::Created, None)),
+    }
+}
+
+/// Locks the row of the run until the surrounding transaction ends and returns its state, which
+/// therefore cannot change before the transaction is committed.
+pub fn lock_state(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<RunState, ServerError> {
+    use tlms::schema::trekkie_runs::dsl::{id, trekkie_runs};
+
+    let trekkie_run = match trekkie_runs
+        .filter(id.eq(run_id))
+        .for_update()
+        .first::<TrekkieRun>(database_connection)
+    {
+        Ok(found_run) => found_run,
+        Err(diesel::result::Error::NotFound) => return Err(ServerError::NotFound),
+        Err(e) => {
+            error!("database error while locking run {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    run_state(&trekkie_run, database_connection).map(|(state, _)| state)
+}
+
+/// Stores the new state of the run, meant to be called inside the transaction which changes the
+/// run accordingly.
+pub fn set_state(
+    run_id: Uuid,
+    state: RunState,
+    aborted_reason: Option<&str>,
+    database_connection: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::trekkie_run_activity::dsl as activity;
+
+    diesel::insert_into(activity::trekkie_run_activity)
+        .values(&RunActivity {
+            trekkie_run: run_id,
+            created_at: Utc::now().naive_utc(),
+            last_point_at: None,
+            state: state.as_str().to_string(),
+            aborted_reason: aborted_reason.map(str::to_string),
+        })
+        .on_conflict(activity::trekkie_run)
+        .do_update()
+        .set((
+            activity::state.eq(state.as_str()),
+            activity::aborted_reason.eq(aborted_reason),
+        ))
+        .execute(database_connection)?;
+
+    Ok(())
+}
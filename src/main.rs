@@ -1,25 +1,60 @@
+mod aggregation;
+mod chemo;
+mod config;
+mod correlation;
+mod formats;
+mod ingest;
+mod lifecycle;
+mod maintenance;
+mod models;
+mod processing;
+mod reaper;
 mod routes;
+mod schema;
+mod session;
+mod simplification;
+mod statistics;
 mod structs;
+mod validation;
 
+use chemo::ChemoForwarder;
+use config::Regions;
+use ingest::IngestConfig;
+use session::SessionKeys;
 use structs::Args;
 
 use actix_identity::IdentityMiddleware;
 use actix_session::storage::RedisActorSessionStore;
 use actix_session::SessionMiddleware;
-use actix_web::{cookie::Key, middleware::Logger, web, App, HttpServer};
+use actix_web::{
+    http::StatusCode,
+    middleware::{from_fn, ErrorHandlers, Logger},
+    web, App, HttpServer,
+};
 use clap::Parser;
 use diesel::r2d2::ConnectionManager;
 use diesel::r2d2::Pool;
 use diesel::PgConnection;
-use log::{debug, info};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::{debug, error, info, warn};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 use std::env;
 use std::fs;
+use std::time::Duration;
 
 type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+/// migrations of the tables owned by trekkie, see [`schema`]
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// upper bound of the json size of a single submitted gps point
+const MAX_POINT_JSON_SIZE: usize = 800;
+
+/// maximum size of json bodies after decompression, fits a full batch of live gps points
+const JSON_BODY_LIMIT: usize = routes::run::MAX_BATCH_SIZE * MAX_POINT_JSON_SIZE;
+
 pub fn create_db_pool() -> DbPool {
     let default_postgres_host = String::from("localhost:5433");
     let default_postgres_port = String::from("5432");
@@ -45,6 +80,40 @@ pub fn create_db_pool() -> DbPool {
     Pool::new(manager).expect("Failed to create pool.")
 }
 
+/// Applies the trekkie migrations which are not yet applied. If they are skipped because the
+/// schema is managed elsewhere, pending migrations are only reported. Exits if the database
+/// cannot be migrated, trekkie cannot work without its tables.
+pub fn run_migrations(pool: &DbPool, skip: bool) {
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if skip {
+        match database_connection.has_pending_migration(MIGRATIONS) {
+            Ok(false) => {}
+            Ok(true) => warn!("database migrations are skipped but some are not applied yet"),
+            Err(e) => warn!("cannot check for pending database migrations {:?}", e),
+        }
+        return;
+    }
+
+    match database_connection.run_pending_migrations(MIGRATIONS) {
+        Ok(applied) => {
+            for migration in applied {
+                info!("applied migration {}", migration);
+            }
+        }
+        Err(e) => {
+            error!("cannot run database migrations {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 pub fn get_redis_uri() -> String {
     let default_redis_port = "6379".to_string();
     let default_redis_host = "127.0.0.1".to_string();
@@ -78,17 +147,68 @@ async fn main() -> std::io::Result<()> {
     info!("Listening on: {}:{}", host, port);
 
     let connection_pool = web::Data::new(create_db_pool());
-    let secret_key = Key::generate();
+    run_migrations(&connection_pool, args.skip_migrations);
+    let regions = {
+        let mut database_connection = connection_pool
+            .get()
+            .expect("cannot get connection from connection pool");
+        web::Data::new(Regions::load(&args, &mut database_connection))
+    };
+    let ingest_config = web::Data::new(IngestConfig::from_args(&args));
+
+    if args.renormalize_v1_runs {
+        let updated = maintenance::renormalize_v1_runs(&connection_pool, &regions);
+        info!("re-normalized {} v1 runs", updated);
+        return Ok(());
+    }
+
+    let session_keys = web::Data::new(SessionKeys::load(&args));
+    let chemo = web::Data::new(ChemoForwarder::start(
+        args.chemo_grpc.clone(),
+        args.chemo_queue_size,
+        args.forward_uploads,
+    ));
+
+    if args.aggregation_interval > 0 {
+        aggregation::start(
+            connection_pool.clone(),
+            Duration::from_secs(args.aggregation_interval * 60),
+        );
+    }
+
+    if args.idle_run_timeout > 0 {
+        reaper::start(
+            connection_pool.clone(),
+            chrono::Duration::minutes(args.idle_run_timeout),
+            args.max_speed,
+        );
+    }
 
     HttpServer::new(move || {
         App::new()
+            .wrap(
+                ErrorHandlers::new()
+                    .handler(StatusCode::UNAUTHORIZED, routes::unauthorized_handler),
+            )
             .wrap(IdentityMiddleware::default())
             .wrap(SessionMiddleware::new(
                 RedisActorSessionStore::new(get_redis_uri()),
-                secret_key.clone(),
+                session_keys.current.clone(),
             ))
+            .wrap(from_fn(session::migrate_session_cookie))
             .wrap(Logger::default())
             .app_data(connection_pool.clone())
+            .app_data(chemo.clone())
+            .app_data(session_keys.clone())
+            .app_data(regions.clone())
+            .app_data(ingest_config.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(JSON_BODY_LIMIT)
+                    .error_handler(routes::extractor_error),
+            )
+            .app_data(web::QueryConfig::default().error_handler(routes::extractor_error))
+            .app_data(web::PathConfig::default().error_handler(routes::extractor_error))
             .service(
                 web::scope("/v1")
                     .service(routes::run::travel_file_upload)
@@ -99,11 +219,28 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/v2")
                     .service(routes::run::travel_file_upload)
+                    .service(routes::import::bulk_import)
                     .service(routes::run::travel_submit_run_v2)
+                    .service(routes::run::list_runs)
+                    .service(routes::run::get_run)
+                    .service(routes::run::run_stats)
+                    .service(routes::track::export_track)
+                    .service(routes::track::replay_track)
+                    .service(routes::track::clean_track)
+                    .service(routes::correlation::correlate)
+                    .service(routes::correlation::get_correlation)
+                    .service(routes::region::reporting_points)
+                    .service(routes::status::status)
                     .service(routes::run::submit_gps_live)
+                    .service(routes::run::submit_gps_live_batch)
+                    .service(routes::live::live_socket)
                     .service(routes::run::terminate_run)
+                    .service(routes::run::abort_run)
                     .service(routes::user::user_create)
-                    .service(routes::user::user_login),
+                    .service(routes::user::user_login)
+                    .service(routes::token::token_create)
+                    .service(routes::token::token_list)
+                    .service(routes::token::token_revoke),
             )
             .service(
                 SwaggerUi::new("/swagger-ui/{_:.*}")
@@ -0,0 +1,92 @@
+use crate::config::{local_to_utc, Regions};
+use crate::models::V1NormalizedRun;
+use crate::routes::run::{V1_APP_COMMIT, V1_APP_NAME};
+use crate::DbPool;
+
+use tlms::trekkie::TrekkieRun;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+use log::{error, info, warn};
+use uuid::Uuid;
+
+use std::collections::HashSet;
+
+/// offset old trekkie versions subtracted from the times of v1 submissions
+const LEGACY_V1_SHIFT_HOURS: i64 = 2;
+
+/// Before the timezone fix trekkie subtracted two hours from the local wall clock times of every
+/// v1 submission, which is only correct during central european summer time. This restores the
+/// wall clock time, converts it to utc with the timezone of the region and marks the run as
+/// normalized, so running it again does not touch it. Returns the amount of updated runs.
+pub fn renormalize_v1_runs(pool: &DbPool, regions: &Regions) -> usize {
+    let mut database_connection = pool
+        .get()
+        .expect("cannot get connection from connection pool");
+
+    use crate::schema::trekkie_v1_normalized_runs::dsl::trekkie_v1_normalized_runs;
+    use crate::schema::trekkie_v1_normalized_runs::trekkie_run as normalized_run;
+    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
+    use tlms::schema::trekkie_runs::{app_commit, app_name, end_time, id, start_time};
+
+    let normalized: HashSet<Uuid> = trekkie_v1_normalized_runs
+        .select(normalized_run)
+        .load::<Uuid>(&mut database_connection)
+        .expect("cannot load normalized v1 runs")
+        .into_iter()
+        .collect();
+
+    let v1_runs = trekkie_runs
+        .filter(app_name.eq(V1_APP_NAME))
+        .filter(app_commit.eq(V1_APP_COMMIT))
+        .load::<TrekkieRun>(&mut database_connection)
+        .expect("cannot load v1 runs");
+
+    let convert = |stored: NaiveDateTime, region: i64| -> Option<NaiveDateTime> {
+        let wall_clock = stored + Duration::hours(LEGACY_V1_SHIFT_HOURS);
+        local_to_utc(&regions.timezone(region), wall_clock).map(|time| time.naive_utc())
+    };
+
+    let mut updated = 0;
+    for run in v1_runs.iter().filter(|run| !normalized.contains(&run.id)) {
+        let (new_start, new_end) = match (
+            convert(run.start_time, run.region),
+            convert(run.end_time, run.region),
+        ) {
+            (Some(new_start), Some(new_end)) => (new_start, new_end),
+            _ => {
+                warn!(
+                    "times of run {} do not exist in its timezone, skipping",
+                    run.id
+                );
+                continue;
+            }
+        };
+        let shift = (new_start - run.start_time).num_minutes() as i32;
+
+        let result = database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::update(trekkie_runs)
+                .filter(id.eq(run.id))
+                .set((start_time.eq(new_start), end_time.eq(new_end)))
+                .execute(conn)?;
+
+            diesel::insert_into(trekkie_v1_normalized_runs)
+                .values(&V1NormalizedRun {
+                    trekkie_run: run.id,
+                    shifted_by_minutes: shift,
+                    normalized_at: Utc::now().naive_utc(),
+                })
+                .execute(conn)
+        });
+
+        match result {
+            Ok(_) => {
+                info!("shifted v1 run {} by {} minutes", run.id, shift);
+                updated += 1;
+            }
+            Err(e) => error!("cannot normalize v1 run {} {:?}", run.id, e),
+        }
+    }
+
+    updated
+}
@@ -0,0 +1,120 @@
+use crate::schema::*;
+
+use chrono::NaiveDateTime;
+use diesel::{AsChangeset, Insertable, Queryable};
+use uuid::Uuid;
+
+/// Long lived api token of a user, only the hash of the secret is stored
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = trekkie_api_tokens)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub owner: Uuid,
+    pub name: String,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+    pub revoked: bool,
+}
+
+/// Marks a v1 run whose times are stored in true utc
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = trekkie_v1_normalized_runs)]
+pub struct V1NormalizedRun {
+    pub trekkie_run: Uuid,
+    pub shifted_by_minutes: i32,
+    pub normalized_at: NaiveDateTime,
+}
+
+/// Position of the cleaned track of a run, see [`crate::processing`]
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = trekkie_cleaned_points)]
+pub struct CleanedPoint {
+    pub trekkie_run: Uuid,
+    pub timestamp: NaiveDateTime,
+    pub lat: f64,
+    pub lon: f64,
+    pub elevation: Option<f64>,
+    /// id of the gps point the position was derived from
+    pub raw_point: i64,
+}
+
+/// Statistics of a run, see [`crate::statistics`]
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset)]
+#[diesel(table_name = trekkie_run_stats, primary_key(trekkie_run), treat_none_as_null = true)]
+pub struct RunStats {
+    pub trekkie_run: Uuid,
+    pub points: i64,
+    pub distance: f64,
+    pub duration: f64,
+    pub moving_time: f64,
+    pub average_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    pub longest_gap: Option<f64>,
+    pub mean_accuracy: Option<f64>,
+    pub intervals_1s: i64,
+    pub intervals_2s: i64,
+    pub intervals_5s: i64,
+    pub intervals_10s: i64,
+    pub intervals_30s: i64,
+    pub intervals_longer: i64,
+    pub computed_at: NaiveDateTime,
+}
+
+/// Location candidate of a reporting point from one telegram of a run, see
+/// [`crate::correlation`]
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = trekkie_correlations)]
+pub struct Correlation {
+    pub trekkie_run: Uuid,
+    /// id of the r09 telegram
+    pub telegram: i64,
+    pub time: NaiveDateTime,
+    pub region: i64,
+    pub reporting_point: i32,
+    pub junction: i32,
+    pub request_status: i16,
+    pub lat: f64,
+    pub lon: f64,
+    /// seconds between the telegram and the closest gps fix
+    pub fix_delta: f64,
+    /// between 0 and 1
+    pub confidence: f64,
+    pub created_at: NaiveDateTime,
+    /// reported accuracy of the closest gps fix in meters
+    pub accuracy: Option<f64>,
+}
+
+/// Position of a reporting point aggregated from the correlations of all runs, see
+/// [`crate::aggregation`]
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = trekkie_reporting_points)]
+pub struct ReportingPoint {
+    pub region: i64,
+    pub reporting_point: i32,
+    pub lat: f64,
+    pub lon: f64,
+    /// amount of candidates the position is based on
+    pub samples: i32,
+    /// amount of candidates which were rejected as outliers
+    pub outliers: i32,
+    /// amount of runs the used candidates come from
+    pub runs: i32,
+    /// weighted root mean square distance of the used candidates from the position in meters
+    pub spread: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Creation and last point time and state of a run, see [`crate::reaper`] and
+/// [`crate::lifecycle`]
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[diesel(table_name = trekkie_run_activity)]
+pub struct RunActivity {
+    pub trekkie_run: Uuid,
+    pub created_at: NaiveDateTime,
+    /// missing as long as the run received no point
+    pub last_point_at: Option<NaiveDateTime>,
+    /// one of the [`crate::lifecycle::RunState`] names
+    pub state: String,
+    pub aborted_reason: Option<String>,
+}
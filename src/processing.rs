@@ -0,0 +1,600 @@
+use crate::models::CleanedPoint;
+use crate::routes::ServerError;
+
+use tlms::locations::gps::GpsPoint;
+
+use chrono::NaiveDateTime;
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// mean earth radius in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// strongest plausible change of speed between two points in meters per second squared
+const MAX_ACCELERATION: f64 = 8.0;
+
+/// after this many outliers in a row the track is assumed to have really moved, e.g. because the
+/// point before was the bad one, and the current point becomes the new reference
+const MAX_CONSECUTIVE_OUTLIERS: usize = 5;
+
+/// measurement error in meters for points without accuracy
+pub const DEFAULT_ACCURACY: f64 = 10.0;
+
+/// lower bound of the measurement error, phones tend to report optimistic accuracies
+pub const MIN_ACCURACY: f64 = 3.0;
+
+/// standard deviation of the acceleration the smoother expects in meters per second squared
+const PROCESS_NOISE: f64 = 1.0;
+
+/// maximum amount of rows per insert statement
+const INSERT_CHUNK_SIZE: usize = 5000;
+
+/// Outcome of cleaning the track of a run
+#[derive(Serialize, Deserialize, ToSchema, Debug, Default)]
+pub struct CleaningReport {
+    /// amount of stored gps points
+    pub raw_points: usize,
+    /// points which share their timestamp with an earlier point
+    pub duplicate_times: usize,
+    /// points which were dropped because of implausible speed or acceleration
+    pub outliers: usize,
+    /// amount of points of the cleaned track
+    pub cleaned_points: usize,
+}
+
+/// great circle distance between two positions in meters
+pub fn distance(lat_a: f64, lon_a: f64, lat_b: f64, lon_b: f64) -> f64 {
+    let d_lat = (lat_b - lat_a).to_radians();
+    let d_lon = (lon_b - lon_a).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat_a.to_radians().cos() * lat_b.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+/// seconds between two points
+fn seconds_between(from: &GpsPoint, to: &GpsPoint) -> f64 {
+    (to.timestamp - from.timestamp).num_milliseconds() as f64 / 1000.0
+}
+
+/// Keeps the first point of every timestamp, the smoother needs strictly increasing times. The
+/// points have to be ordered by time. Returns the kept points and the amount of dropped ones.
+fn drop_duplicate_times(points: Vec<GpsPoint>) -> (Vec<GpsPoint>, usize) {
+    let mut kept: Vec<GpsPoint> = Vec::with_capacity(points.len());
+    let mut duplicates = 0;
+
+    for point in points {
+        if kept
+            .last()
+            .is_some_and(|last| last.timestamp == point.timestamp)
+        {
+            duplicates += 1;
+        } else {
+            kept.push(point);
+        }
+    }
+
+    (kept, duplicates)
+}
+
+/// Drops points which can only be reached with a speed above max_speed in meters per second or an
+/// implausible acceleration from the last kept point. The points have to be ordered by time
+/// without duplicate timestamps.
+fn remove_outliers(points: Vec<GpsPoint>, max_speed: f64) -> (Vec<GpsPoint>, usize) {
+    let mut kept: Vec<GpsPoint> = Vec::with_capacity(points.len());
+    let mut last_speed: Option<f64> = None;
+    let mut consecutive = 0;
+    let mut outliers = 0;
+
+    for point in points {
+        let last = match kept.last() {
+            Some(last) => last,
+            None => {
+                kept.push(point);
+                continue;
+            }
+        };
+
+        let seconds = seconds_between(last, &point);
+        let speed = distance(last.lat, last.lon, point.lat, point.lon) / seconds;
+        let acceleration =
+            last_speed.map_or(0.0, |last_speed| (speed - last_speed).abs() / seconds);
+
+        if (speed > max_speed || acceleration > MAX_ACCELERATION)
+            && consecutive < MAX_CONSECUTIVE_OUTLIERS
+        {
+            consecutive += 1;
+            outliers += 1;
+            continue;
+        }
+
+        // a new reference point has no trustworthy speed
+        last_speed = (consecutive < MAX_CONSECUTIVE_OUTLIERS).then_some(speed);
+        consecutive = 0;
+        kept.push(point);
+    }
+
+    (kept, outliers)
+}
+
+/// state of the constant velocity model along one axis with its covariance
+#[derive(Clone, Copy)]
+struct AxisState {
+    position: f64,
+    velocity: f64,
+    covariance: [[f64; 2]; 2],
+}
+
+impl AxisState {
+    fn predict(&self, seconds: f64) -> AxisState {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let q = PROCESS_NOISE * PROCESS_NOISE;
+
+        AxisState {
+            position: self.position + seconds * self.velocity,
+            velocity: self.velocity,
+            covariance: [
+                [
+                    p00 + seconds * (p10 + p01)
+                        + seconds * seconds * p11
+                        + q * seconds.powi(4) / 4.0,
+                    p01 + seconds * p11 + q * seconds.powi(3) / 2.0,
+                ],
+                [
+                    p10 + seconds * p11 + q * seconds.powi(3) / 2.0,
+                    p11 + q * seconds * seconds,
+                ],
+            ],
+        }
+    }
+
+    fn update(&self, measured: f64, variance: f64) -> AxisState {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let innovation = p00 + variance;
+        let gain = [p00 / innovation, p10 / innovation];
+        let residual = measured - self.position;
+
+        AxisState {
+            position: self.position + gain[0] * residual,
+            velocity: self.velocity + gain[1] * residual,
+            covariance: [
+                [p00 - gain[0] * p00, p01 - gain[0] * p01],
+                [p10 - gain[1] * p00, p11 - gain[1] * p01],
+            ],
+        }
+    }
+}
+
+/// Kalman filter with constant velocity model followed by a Rauch-Tung-Striebel smoother along
+/// one axis. Takes the measured positions in meters with their variance and the seconds since the
+/// previous measurement, returns the smoothed positions.
+fn smooth_axis(measurements: &[(f64, f64, f64)]) -> Vec<f64> {
+    let (first, first_variance, _) = match measurements.first() {
+        Some(first) => *first,
+        None => return Vec::new(),
+    };
+
+    let mut filtered = Vec::with_capacity(measurements.len());
+    let mut predicted = Vec::with_capacity(measurements.len());
+    let mut state = AxisState {
+        position: first,
+        velocity: 0.0,
+        covariance: [[first_variance, 0.0], [0.0, 100.0]],
+    };
+    filtered.push(state);
+    predicted.push(state);
+
+    for (measured, variance, seconds) in &measurements[1..] {
+        let prediction = state.predict(*seconds);
+        state = prediction.update(*measured, *variance);
+        predicted.push(prediction);
+        filtered.push(state);
+    }
+
+    // backwards pass, every state is corrected by the smoothed successor
+    let mut smoothed = vec![state.position; measurements.len()];
+    let mut next_position = state.position;
+    let mut next_velocity = state.velocity;
+
+    for index in (0..measurements.len() - 1).rev() {
+        let current = filtered[index];
+        let prediction = predicted[index + 1];
+        let seconds = measurements[index + 1].2;
+
+        let [[p00, p01], [p10, p11]] = current.covariance;
+        // current covariance times the transposed transition matrix
+        let cross = [[p00 + seconds * p01, p01], [p10 + seconds * p11, p11]];
+
+        let [[q00, q01], [q10, q11]] = prediction.covariance;
+        let determinant = q00 * q11 - q01 * q10;
+        if determinant.abs() < f64::EPSILON {
+            smoothed[index] = current.position;
+            next_position = current.position;
+            next_velocity = current.velocity;
+            continue;
+        }
+        let inverse = [
+            [q11 / determinant, -q01 / determinant],
+            [-q10 / determinant, q00 / determinant],
+        ];
+        let gain = [
+            [
+                cross[0][0] * inverse[0][0] + cross[0][1] * inverse[1][0],
+                cross[0][0] * inverse[0][1] + cross[0][1] * inverse[1][1],
+            ],
+            [
+                cross[1][0] * inverse[0][0] + cross[1][1] * inverse[1][0],
+                cross[1][0] * inverse[0][1] + cross[1][1] * inverse[1][1],
+            ],
+        ];
+
+        let position_error = next_position - prediction.position;
+        let velocity_error = next_velocity - prediction.velocity;
+        next_position =
+            current.position + gain[0][0] * position_error + gain[0][1] * velocity_error;
+        next_velocity =
+            current.velocity + gain[1][0] * position_error + gain[1][1] * velocity_error;
+        smoothed[index] = next_position;
+    }
+
+    smoothed
+}
+
+/// Smooths the positions of the points, which are projected onto a plane around the first point
+/// for this. The points have to be ordered by time without duplicate timestamps.
+fn smooth(points: &[GpsPoint]) -> Vec<(f64, f64)> {
+    let (origin_lat, origin_lon) = match points.first() {
+        Some(first) => (first.lat, first.lon),
+        None => return Vec::new(),
+    };
+    let meters_per_degree = EARTH_RADIUS.to_radians();
+    let lon_scale = meters_per_degree * origin_lat.to_radians().cos().max(0.01);
+
+    let mut north = Vec::with_capacity(points.len());
+    let mut east = Vec::with_capacity(points.len());
+    for (index, point) in points.iter().enumerate() {
+        let accuracy = point
+            .accuracy
+            .filter(|accuracy| accuracy.is_finite() && *accuracy > 0.0)
+            .unwrap_or(DEFAULT_ACCURACY)
+            .max(MIN_ACCURACY);
+        let seconds = match index {
+            0 => 0.0,
+            _ => seconds_between(&points[index - 1], point),
+        };
+
+        north.push((
+            (point.lat - origin_lat) * meters_per_degree,
+            accuracy * accuracy,
+            seconds,
+        ));
+        east.push((
+            (point.lon - origin_lon) * lon_scale,
+            accuracy * accuracy,
+            seconds,
+        ));
+    }
+
+    smooth_axis(&north)
+        .into_iter()
+        .zip(smooth_axis(&east))
+        .map(|(north, east)| {
+            (
+                origin_lat + north / meters_per_degree,
+                origin_lon + east / lon_scale,
+            )
+        })
+        .collect()
+}
+
+/// Time and position of every point of the run's track in timestamp order. The cleaned track is
+/// used if the run was cleaned, otherwise the raw gps points.
+pub fn load_track(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<(NaiveDateTime, f64, f64)>, ServerError> {
+    use crate::schema::trekkie_cleaned_points::dsl as cleaned;
+    use tlms::schema::gps_points::dsl as gps;
+
+    let track = cleaned::trekkie_cleaned_points
+        .filter(cleaned::trekkie_run.eq(run_id))
+        .order(cleaned::timestamp.asc())
+        .select((cleaned::timestamp, cleaned::lat, cleaned::lon))
+        .load::<(NaiveDateTime, f64, f64)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading cleaned track {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    if !track.is_empty() {
+        return Ok(track);
+    }
+
+    gps::gps_points
+        .filter(gps::trekkie_run.eq(run_id))
+        .order(gps::timestamp.asc())
+        .select((gps::timestamp, gps::lat, gps::lon))
+        .load::<(NaiveDateTime, f64, f64)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading gps points {:?}", e);
+            ServerError::InternalError
+        })
+}
+
+/// Removes outliers from the raw gps points of the run, smooths the remaining positions and
+/// replaces the cleaned track of the run with the result. The speed limit is the one of the
+/// point validation, so points which were accepted are not dropped as outliers for their speed.
+pub fn clean_run(
+    run_id: Uuid,
+    max_speed: f64,
+    database_connection: &mut PgConnection,
+) -> Result<CleaningReport, ServerError> {
+    use crate::schema::trekkie_cleaned_points::dsl::trekkie_cleaned_points;
+    use crate::schema::trekkie_cleaned_points::trekkie_run as cleaned_run;
+    use tlms::schema::gps_points::dsl::gps_points;
+    use tlms::schema::gps_points::{id, timestamp, trekkie_run};
+
+    let raw_points = gps_points
+        .filter(trekkie_run.eq(run_id))
+        .order((timestamp.asc(), id.asc()))
+        .load::<GpsPoint>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading gps points {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    let mut report = CleaningReport {
+        raw_points: raw_points.len(),
+        ..Default::default()
+    };
+
+    let (points, duplicate_times) = drop_duplicate_times(raw_points);
+    report.duplicate_times = duplicate_times;
+
+    let (points, outliers) = remove_outliers(points, max_speed);
+    report.outliers = outliers;
+
+    let cleaned: Vec<CleanedPoint> = points
+        .iter()
+        .zip(smooth(&points))
+        .map(|(point, (lat, lon))| CleanedPoint {
+            trekkie_run: run_id,
+            timestamp: point.timestamp,
+            lat,
+            lon,
+            elevation: point.elevation,
+            raw_point: point.id,
+        })
+        .collect();
+    report.cleaned_points = cleaned.len();
+
+    database_connection
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(trekkie_cleaned_points.filter(cleaned_run.eq(run_id))).execute(conn)?;
+
+            for chunk in cleaned.chunks(INSERT_CHUNK_SIZE) {
+                diesel::insert_into(trekkie_cleaned_points)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            Ok(())
+        })
+        .map_err(|e| {
+            error!("while trying to store cleaned track {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::formats::tests::{assert_close, time};
+
+    /// meters per degree of latitude
+    const METERS_PER_DEGREE: f64 = EARTH_RADIUS * std::f64::consts::PI / 180.0;
+
+    /// raw point the given seconds after noon, north and east in meters from 51° N 13° E
+    pub(crate) fn gps_point(seconds: u32, north: f64, east: f64) -> GpsPoint {
+        GpsPoint {
+            id: i64::from(seconds),
+            trekkie_run: Uuid::nil(),
+            timestamp: time(12, seconds / 60, seconds % 60),
+            lat: 51.0 + north / METERS_PER_DEGREE,
+            lon: 13.0 + east / (METERS_PER_DEGREE * 51.0f64.to_radians().cos()),
+            elevation: None,
+            accuracy: None,
+            vertical_accuracy: None,
+            bearing: None,
+            speed: None,
+        }
+    }
+
+    /// points moving north with the given speed in meters per second, one per second
+    fn straight(count: u32, speed: f64) -> Vec<GpsPoint> {
+        (0..count)
+            .map(|second| gps_point(second, f64::from(second) * speed, 0.0))
+            .collect()
+    }
+
+    fn ids(points: &[GpsPoint]) -> Vec<i64> {
+        points.iter().map(|point| point.id).collect()
+    }
+
+    #[test]
+    fn distance_known_answer() {
+        assert!((distance(51.0, 13.0, 52.0, 13.0) - 111_194.9).abs() < 0.1);
+        assert!((distance(0.0, 13.0, 0.0, 14.0) - 111_194.9).abs() < 0.1);
+        // a quarter of the meridian from the equator to the pole
+        assert!((distance(0.0, 13.0, 90.0, 13.0) - 10_007_543.4).abs() < 0.1);
+        assert_eq!(distance(51.0, 13.0, 51.0, 13.0), 0.0);
+    }
+
+    #[test]
+    fn duplicate_times() {
+        let (kept, duplicates) = drop_duplicate_times(Vec::new());
+        assert!(kept.is_empty());
+        assert_eq!(duplicates, 0);
+
+        let points = vec![
+            gps_point(0, 0.0, 0.0),
+            gps_point(1, 10.0, 0.0),
+            GpsPoint {
+                id: 100,
+                ..gps_point(1, 50.0, 0.0)
+            },
+            GpsPoint {
+                id: 101,
+                ..gps_point(1, 60.0, 0.0)
+            },
+            gps_point(2, 20.0, 0.0),
+        ];
+        let (kept, duplicates) = drop_duplicate_times(points);
+        assert_eq!(ids(&kept), [0, 1, 2]);
+        assert_eq!(duplicates, 2);
+    }
+
+    #[test]
+    fn outliers_few_points() {
+        let (kept, outliers) = remove_outliers(Vec::new(), 70.0);
+        assert!(kept.is_empty());
+        assert_eq!(outliers, 0);
+
+        let (kept, outliers) = remove_outliers(straight(1, 10.0), 70.0);
+        assert_eq!(ids(&kept), [0]);
+        assert_eq!(outliers, 0);
+
+        // the first point is always kept, a jump of one kilometer in a second is not
+        let points = vec![gps_point(0, 0.0, 0.0), gps_point(1, 1000.0, 0.0)];
+        let (kept, outliers) = remove_outliers(points, 70.0);
+        assert_eq!(ids(&kept), [0]);
+        assert_eq!(outliers, 1);
+    }
+
+    #[test]
+    fn outliers_single_jump() {
+        let mut points = straight(10, 10.0);
+        points[5] = gps_point(5, 50.0, 500.0);
+
+        let (kept, outliers) = remove_outliers(points, 70.0);
+        assert_eq!(ids(&kept), [0, 1, 2, 3, 4, 6, 7, 8, 9]);
+        assert_eq!(outliers, 1);
+    }
+
+    #[test]
+    fn outliers_speed_limit() {
+        let (kept, outliers) = remove_outliers(straight(10, 60.0), 70.0);
+        assert_eq!(kept.len(), 10);
+        assert_eq!(outliers, 0);
+
+        // after five outliers in a row the next point becomes the new reference
+        let (kept, outliers) = remove_outliers(straight(10, 60.0), 50.0);
+        assert_eq!(ids(&kept), [0, 6]);
+        assert_eq!(outliers, 8);
+    }
+
+    #[test]
+    fn outliers_acceleration() {
+        // braking from 20 m/s to a standstill within a second
+        let mut points = straight(5, 20.0);
+        points.push(gps_point(5, 80.0, 0.0));
+        points.push(gps_point(6, 80.0, 0.0));
+
+        let (kept, outliers) = remove_outliers(points, 70.0);
+        assert_eq!(ids(&kept), [0, 1, 2, 3, 4]);
+        assert_eq!(outliers, 2);
+    }
+
+    #[test]
+    fn outliers_new_reference() {
+        let mut points = straight(3, 10.0);
+        points.extend((3..10).map(|second| gps_point(second, f64::from(second) * 10.0, 5000.0)));
+
+        let (kept, outliers) = remove_outliers(points, 70.0);
+        assert_eq!(ids(&kept), [0, 1, 2, 8, 9]);
+        assert_eq!(outliers, 5);
+    }
+
+    #[test]
+    fn smooth_axis_few_measurements() {
+        assert!(smooth_axis(&[]).is_empty());
+
+        let smoothed = smooth_axis(&[(5.0, 25.0, 0.0)]);
+        assert_eq!(smoothed.len(), 1);
+        assert_close(smoothed[0], 5.0);
+
+        let smoothed = smooth_axis(&[(5.0, 25.0, 0.0), (5.0, 25.0, 1.0)]);
+        assert_eq!(smoothed.len(), 2);
+        assert_close(smoothed[0], 5.0);
+        assert_close(smoothed[1], 5.0);
+    }
+
+    #[test]
+    fn smooth_axis_same_time() {
+        // two equally accurate fixes at the same time are averaged
+        let smoothed = smooth_axis(&[(0.0, 1.0, 0.0), (2.0, 1.0, 0.0)]);
+        assert_close(smoothed[0], 1.0);
+        assert_close(smoothed[1], 1.0);
+
+        // a more accurate fix pulls the position towards it
+        let smoothed = smooth_axis(&[(0.0, 1.0, 0.0), (4.0, 3.0, 0.0)]);
+        assert_close(smoothed[0], 1.0);
+        assert_close(smoothed[1], 1.0);
+    }
+
+    #[test]
+    fn smooth_axis_constant_velocity() {
+        let measurements: Vec<(f64, f64, f64)> = (0..20)
+            .map(|second| (f64::from(second) * 10.0, 0.01, 1.0))
+            .collect();
+
+        for (second, position) in smooth_axis(&measurements).into_iter().enumerate() {
+            assert!(
+                (position - second as f64 * 10.0).abs() < 0.1,
+                "{} is not close to {}",
+                position,
+                second * 10
+            );
+        }
+    }
+
+    #[test]
+    fn smooth_axis_noise() {
+        // standing still with fixes alternating five meters around the true position
+        let measurements: Vec<(f64, f64, f64)> = (0..30)
+            .map(|second| (if second % 2 == 0 { 5.0 } else { -5.0 }, 25.0, 1.0))
+            .collect();
+        let smoothed = smooth_axis(&measurements);
+
+        assert_eq!(smoothed.len(), 30);
+        assert!(smoothed.iter().all(|position| position.abs() < 2.5));
+        assert!(smoothed[5..25].iter().all(|position| position.abs() < 1.0));
+    }
+
+    #[test]
+    fn smooth_points() {
+        assert!(smooth(&[]).is_empty());
+
+        let point = gps_point(0, 0.0, 0.0);
+        let smoothed = smooth(std::slice::from_ref(&point));
+        assert_eq!(smoothed.len(), 1);
+        assert_close(smoothed[0].0, point.lat);
+        assert_close(smoothed[0].1, point.lon);
+
+        // a straight track stays on its line
+        let points: Vec<GpsPoint> = (0..10)
+            .map(|second| GpsPoint {
+                accuracy: Some(3.0),
+                ..gps_point(second, f64::from(second) * 10.0, f64::from(second) * 5.0)
+            })
+            .collect();
+        for (point, (lat, lon)) in points.iter().zip(smooth(&points)) {
+            assert!(distance(point.lat, point.lon, lat, lon) < 1.0);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::lifecycle::RunState;
+use crate::models::RunActivity;
+use crate::routes::run::{delete_run, mark_finished, process_finished_run};
+use crate::routes::ServerError;
+use crate::DbPool;
+
+use actix_web::web;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+};
+use log::{error, info, warn};
+use uuid::Uuid;
+
+use std::collections::HashMap;
+
+/// seconds between two searches for idle runs
+const REAP_INTERVAL: u64 = 5 * 60;
+
+/// What the reaper did with an idle run
+enum Reaped {
+    /// the run received a point or was finished or deleted meanwhile
+    Active,
+    Finished,
+    Deleted,
+}
+
+/// Rechecks an idle run and finishes or deletes it in one transaction. The run row is locked like
+/// while inserting points, so a point which arrives meanwhile is either counted here or waits
+/// until the run was finished.
+fn reap_run(
+    run_id: Uuid,
+    idle: Duration,
+    now: NaiveDateTime,
+    database_connection: &mut PgConnection,
+) -> Result<Reaped, diesel::result::Error> {
+    use crate::schema::trekkie_run_activity::dsl::{
+        last_point_at, trekkie_run, trekkie_run_activity,
+    };
+    use tlms::schema::gps_points::dsl as gps;
+    use tlms::schema::trekkie_runs::dsl::{finished, id, trekkie_runs};
+
+    database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        let run_finished = trekkie_runs
+            .filter(id.eq(run_id))
+            .select(finished)
+            .for_update()
+            .first::<bool>(conn)
+            .optional()?;
+        if run_finished != Some(false) {
+            return Ok(Reaped::Active);
+        }
+
+        // runs without points stay idle since their creation
+        let last_point = trekkie_run_activity
+            .filter(trekkie_run.eq(run_id))
+            .select(last_point_at)
+            .first::<Option<NaiveDateTime>>(conn)?;
+        if last_point.is_some_and(|last| now - last < idle) {
+            return Ok(Reaped::Active);
+        }
+
+        let points = gps::gps_points
+            .filter(gps::trekkie_run.eq(run_id))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        // both log their own errors
+        if points == 0 {
+            delete_run(run_id, conn).map_err(|_| diesel::result::Error::RollbackTransaction)?;
+            Ok(Reaped::Deleted)
+        } else {
+            mark_finished(run_id, conn).map_err(|_| diesel::result::Error::RollbackTransaction)?;
+            Ok(Reaped::Finished)
+        }
+    })
+}
+
+/// Finishes unfinished runs which received no point for the given idle period, exactly like
+/// terminating them, and deletes the ones which never received any point. A run is idle since its
+/// last point or, without points, since its creation. Runs created before the activity was
+/// tracked get a full idle period from now. Returns the amount of finished and deleted runs.
+pub fn reap_idle_runs(
+    idle: Duration,
+    max_speed: f64,
+    database_connection: &mut PgConnection,
+) -> Result<(usize, usize), ServerError> {
+    use crate::schema::trekkie_run_activity::dsl::{trekkie_run, trekkie_run_activity};
+    use tlms::schema::trekkie_runs::dsl::{finished, id, trekkie_runs};
+
+    let unfinished = trekkie_runs
+        .filter(finished.eq(false))
+        .select(id)
+        .load::<Uuid>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading unfinished runs {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    let activities: HashMap<Uuid, RunActivity> = trekkie_run_activity
+        .filter(trekkie_run.eq_any(&unfinished))
+        .load::<RunActivity>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading run activity {:?}", e);
+            ServerError::InternalError
+        })?
+        .into_iter()
+        .map(|activity| (activity.trekkie_run, activity))
+        .collect();
+
+    let now = Utc::now().naive_utc();
+    let mut finished_runs = 0;
+    let mut deleted_runs = 0;
+    for run_id in unfinished {
+        let last_activity = match activities.get(&run_id) {
+            Some(activity) => activity.last_point_at.unwrap_or(activity.created_at),
+            None => {
+                diesel::insert_into(trekkie_run_activity)
+                    .values(&RunActivity {
+                        trekkie_run: run_id,
+                        created_at: now,
+                        last_point_at: None,
+                        state: RunState::Created.as_str().to_string(),
+                        aborted_reason: None,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(database_connection)
+                    .map_err(|e| {
+                        error!("while trying to insert run activity {:?}", e);
+                        ServerError::InternalError
+                    })?;
+                continue;
+            }
+        };
+
+        if now - last_activity < idle {
+            continue;
+        }
+
+        // a single broken run must not keep the others from being reaped
+        match reap_run(run_id, idle, now, database_connection) {
+            Ok(Reaped::Active) => {}
+            Ok(Reaped::Deleted) => {
+                info!("deleted run {} which never received a point", run_id);
+                deleted_runs += 1;
+            }
+            Ok(Reaped::Finished) => {
+                info!(
+                    "finished run {} which received no point since {}",
+                    run_id, last_activity
+                );
+                finished_runs += 1;
+
+                // cleaning and correlating take a while, so they run after the lock is released
+                process_finished_run(run_id, max_speed, database_connection);
+            }
+            Err(e) => warn!("cannot reap idle run {} {:?}", run_id, e),
+        }
+    }
+
+    Ok((finished_runs, deleted_runs))
+}
+
+/// spawns the background task which finishes or deletes runs idle for the given period, max_speed
+/// is the speed limit of the point validation used for cleaning the finished runs
+pub fn start(pool: web::Data<DbPool>, idle: Duration, max_speed: f64) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(REAP_INTERVAL));
+
+        loop {
+            ticker.tick().await;
+
+            let pool = pool.clone();
+            let result = web::block(move || match pool.get() {
+                Ok(mut database_connection) => {
+                    reap_idle_runs(idle, max_speed, &mut database_connection)
+                }
+                Err(e) => {
+                    error!("cannot get connection from connection pool {:?}", e);
+                    Err(ServerError::InternalError)
+                }
+            })
+            .await;
+
+            match result {
+                Ok(Ok((0, 0))) => {}
+                Ok(Ok((finished, deleted))) => {
+                    info!("reaped {} idle and {} empty runs", finished, deleted)
+                }
+                Ok(Err(_)) => error!("cannot reap idle runs"),
+                Err(e) => error!("run reaper panicked {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,184 @@
+use crate::correlation::correlate_run;
+use crate::lifecycle::{run_state, RunState};
+use crate::models::Correlation;
+use crate::routes::{
+    run::fetch_run,
+    user::{fetch_user, Credentials},
+    ServerError,
+};
+use crate::DbPool;
+
+use actix_web::{get, post, web, HttpRequest};
+use chrono::NaiveDateTime;
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Telegram of the run's vehicle with the position it was sent at
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CorrelatedTelegram {
+    /// id of the r09 telegram
+    pub telegram: i64,
+    pub time: NaiveDateTime,
+    pub reporting_point: i32,
+    pub junction: i32,
+    pub request_status: i16,
+    /// position interpolated from the track of the run
+    pub lat: f64,
+    pub lon: f64,
+    /// seconds between the telegram and the closest gps fix
+    pub fix_delta: f64,
+    /// between 0 and 1, halves every 5 seconds of fix delta
+    pub confidence: f64,
+    /// reported accuracy of the closest gps fix in meters
+    pub accuracy: Option<f64>,
+}
+
+impl From<Correlation> for CorrelatedTelegram {
+    fn from(correlation: Correlation) -> Self {
+        CorrelatedTelegram {
+            telegram: correlation.telegram,
+            time: correlation.time,
+            reporting_point: correlation.reporting_point,
+            junction: correlation.junction,
+            request_status: correlation.request_status,
+            lat: correlation.lat,
+            lon: correlation.lon,
+            fix_delta: correlation.fix_delta,
+            confidence: correlation.confidence,
+            accuracy: correlation.accuracy,
+        }
+    }
+}
+
+/// Outcome of the correlation of a run
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CorrelationResult {
+    pub trekkie_run: Uuid,
+    /// false until the run was correlated once
+    pub correlated: bool,
+    /// matched telegrams in time order
+    pub telegrams: Vec<CorrelatedTelegram>,
+}
+
+/// loads the stored correlation of the run
+fn fetch_correlation(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<CorrelatedTelegram>, ServerError> {
+    use crate::schema::trekkie_correlations::dsl::{time, trekkie_correlations, trekkie_run};
+
+    let correlations = trekkie_correlations
+        .filter(trekkie_run.eq(run_id))
+        .order(time.asc())
+        .load::<Correlation>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading correlation {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    Ok(correlations
+        .into_iter()
+        .map(CorrelatedTelegram::from)
+        .collect())
+}
+
+/// Correlates a finished run again with the telegrams of its vehicle, e.g. after telegrams
+/// arrived late or the track was cleaned again. Returns the new correlation.
+#[utoipa::path(
+    post,
+    path = "/v2/trekkie/{id}/correlate",
+    responses(
+        (status = 200, description = "run was correlated", body = CorrelationResult),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is not finished yet, aborted or empty"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[post("/trekkie/{id}/correlate")]
+pub async fn correlate(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<CorrelationResult>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    // start and end time of unfinished runs are not known yet and aborted or empty runs have no
+    // points to correlate
+    if !trekkie_run.finished {
+        return Err(ServerError::Conflict);
+    }
+    if let (RunState::Aborted | RunState::Empty, _) =
+        run_state(&trekkie_run, &mut database_connection)?
+    {
+        return Err(ServerError::Conflict);
+    }
+
+    let stored = correlate_run(&trekkie_run, &mut database_connection)?;
+
+    Ok(web::Json(CorrelationResult {
+        trekkie_run: trekkie_run.id,
+        correlated: stored.is_some() || trekkie_run.correlated,
+        telegrams: fetch_correlation(trekkie_run.id, &mut database_connection)?,
+    }))
+}
+
+/// Returns every telegram of the run's vehicle which was matched with the track, together with
+/// its interpolated position, the time delta to the closest gps fix and a confidence.
+#[utoipa::path(
+    get,
+    path = "/v2/trekkie/{id}/correlation",
+    responses(
+        (status = 200, description = "correlation of the run", body = CorrelationResult),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/trekkie/{id}/correlation")]
+pub async fn get_correlation(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<CorrelationResult>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    Ok(web::Json(CorrelationResult {
+        trekkie_run: trekkie_run.id,
+        correlated: trekkie_run.correlated,
+        telegrams: fetch_correlation(trekkie_run.id, &mut database_connection)?,
+    }))
+}
@@ -0,0 +1,333 @@
+use crate::chemo::{grpc_point, ChemoForwarder};
+use crate::config::{Geofence, Regions};
+use crate::formats::{compression::SizeBudget, import, ImportFormat, UploadedFile};
+use crate::ingest::{ChunkedInsert, IngestConfig, IngestReport, StoredPoint};
+use crate::routes::{
+    run::{
+        check_content_length, create_run, delete_run, fetch_run, finish_run, spool_field,
+        SubmitTravelV2,
+    },
+    user::{fetch_user, Credentials},
+    ServerError,
+};
+use crate::DbPool;
+
+use tlms::grpc::GrpcGpsPoint;
+
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpRequest};
+use diesel::PgConnection;
+use futures::TryStreamExt;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+use zip::ZipArchive;
+
+use std::fs::File;
+use std::io::Read;
+
+/// name of the manifest inside the archive
+const MANIFEST: &str = "manifest.json";
+
+/// maximum amount of manifest entries, every entry creates a run
+const MAX_IMPORT_ENTRIES: usize = 500;
+
+/// Entry of the manifest.json of an import archive
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportManifestEntry {
+    /// path of the track file inside the archive
+    pub file: String,
+    /// format of the track file, detected from name and content if missing
+    pub format: Option<ImportFormat>,
+    #[serde(flatten)]
+    pub run: SubmitTravelV2,
+}
+
+/// Query parameters of the bulk import
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    /// replay the imported points to chemo, defaults to the server configuration
+    pub forward: Option<bool>,
+}
+
+/// Outcome of one manifest entry
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportResult {
+    pub file: String,
+    /// run which was created for the file
+    pub trekkie_run: Option<Uuid>,
+    pub report: Option<IngestReport>,
+    /// why the file could not be imported, no run is kept in this case
+    pub error: Option<String>,
+}
+
+/// Outcome of a bulk import in the order of the manifest
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ImportSummary {
+    pub results: Vec<ImportResult>,
+}
+
+/// stores the points of a file for the freshly created run and finishes it
+fn store_track<R: Read>(
+    file: R,
+    entry: &ImportManifestEntry,
+    run_id: Uuid,
+    forward: bool,
+    ingest_config: &IngestConfig,
+    budget: &mut SizeBudget,
+    geofence: Option<&Geofence>,
+    database_connection: &mut PgConnection,
+) -> Result<(IngestReport, Vec<StoredPoint>), String> {
+    let mut sink = ChunkedInsert::new(
+        run_id,
+        false,
+        database_connection,
+        &ingest_config.file_validation,
+        geofence,
+        forward,
+    );
+    let uploaded = UploadedFile {
+        content_type: None,
+        file_name: Some(entry.file.clone()),
+    };
+    import(file, &uploaded, entry.format, budget, run_id, &mut sink)?;
+
+    let (report, stored) = sink
+        .finish()
+        .map_err(|_| "cannot store gps points".to_string())?;
+    if report.inserted == 0 {
+        return Err("file contains no usable gps points".to_string());
+    }
+
+    finish_run(
+        run_id,
+        ingest_config.validation.max_speed,
+        database_connection,
+    )
+    .map_err(|_| "cannot finish run".to_string())?;
+
+    Ok((report, stored))
+}
+
+/// creates the run of a manifest entry and stores the points of its file
+fn import_entry(
+    archive: &mut ZipArchive<File>,
+    entry: &ImportManifestEntry,
+    owner: Uuid,
+    forward: bool,
+    ingest_config: &IngestConfig,
+    budget: &mut SizeBudget,
+    regions: &Regions,
+    database_connection: &mut PgConnection,
+) -> Result<(Uuid, IngestReport, Vec<StoredPoint>), String> {
+    let file = archive
+        .by_name(&entry.file)
+        .map_err(|_| format!("{} does not exist in the archive", entry.file))?;
+
+    let run_id = create_run(&entry.run, owner, database_connection)
+        .map_err(|_| "cannot create run".to_string())?;
+
+    match store_track(
+        file,
+        entry,
+        run_id,
+        forward,
+        ingest_config,
+        budget,
+        regions.geofence(entry.run.region),
+        database_connection,
+    ) {
+        Ok((report, stored)) => Ok((run_id, report, stored)),
+        Err(e) => {
+            // runs of failed files are not kept, so the import can simply be retried
+            if delete_run(run_id, database_connection).is_err() {
+                warn!("run {} of failed import is left behind", run_id);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Creates and fills the runs of all manifest entries of the archive. Returns the outcome per
+/// file together with the newly stored points of every imported run if they should be replayed.
+fn import_archive(
+    file: File,
+    owner: Uuid,
+    forward: bool,
+    ingest_config: &IngestConfig,
+    regions: &Regions,
+    database_connection: &mut PgConnection,
+) -> Result<(Vec<ImportResult>, Vec<Vec<GrpcGpsPoint>>), ServerError> {
+    let mut archive = ZipArchive::new(file)
+        .map_err(|e| ServerError::InvalidData(format!("upload is no zip archive: {}", e)))?;
+
+    let manifest: Vec<ImportManifestEntry> = {
+        let manifest_file = archive
+            .by_name(MANIFEST)
+            .map_err(|_| ServerError::InvalidData(format!("archive contains no {}", MANIFEST)))?;
+        serde_json::from_reader(manifest_file)
+            .map_err(|e| ServerError::InvalidData(format!("invalid {}: {}", MANIFEST, e)))?
+    };
+
+    if manifest.len() > MAX_IMPORT_ENTRIES {
+        return Err(ServerError::InvalidData(format!(
+            "manifest lists {} files, at most {} are allowed",
+            manifest.len(),
+            MAX_IMPORT_ENTRIES
+        )));
+    }
+
+    // all files together may not decompress beyond the maximum upload size, the declared sizes
+    // reject honest archives before any run is created and the budget catches the others
+    let declared: u64 = manifest
+        .iter()
+        .filter_map(|entry| archive.by_name(&entry.file).ok().map(|file| file.size()))
+        .sum();
+    if declared > ingest_config.max_upload_size {
+        return Err(ServerError::PayloadTooLarge);
+    }
+    let mut budget = SizeBudget::new(ingest_config.max_upload_size);
+
+    let mut results = Vec::with_capacity(manifest.len());
+    let mut replays = Vec::new();
+
+    for entry in manifest {
+        match import_entry(
+            &mut archive,
+            &entry,
+            owner,
+            forward,
+            ingest_config,
+            &mut budget,
+            regions,
+            database_connection,
+        ) {
+            Ok((run_id, report, stored)) => {
+                if forward {
+                    let trekkie_run = fetch_run(run_id, database_connection)?;
+                    replays.push(
+                        stored
+                            .into_iter()
+                            .map(|(time, lat, lon)| grpc_point(&trekkie_run, time, lat, lon))
+                            .collect(),
+                    );
+                }
+
+                results.push(ImportResult {
+                    file: entry.file,
+                    trekkie_run: Some(run_id),
+                    report: Some(report),
+                    error: None,
+                });
+            }
+            Err(_) if budget.exceeded() => {
+                warn!(
+                    "import stopped at {}, archive exceeds the maximum upload size",
+                    entry.file
+                );
+                return Err(ServerError::PayloadTooLarge);
+            }
+            Err(e) => {
+                warn!("cannot import {} {}", entry.file, e);
+                results.push(ImportResult {
+                    file: entry.file,
+                    trekkie_run: None,
+                    report: None,
+                    error: Some(e),
+                });
+            }
+        }
+    }
+
+    Ok((results, replays))
+}
+
+/// Imports a zip archive with many track files and a manifest.json, which lists for every file
+/// the metadata of its run like `POST /v2/trekkie`. One finished run is created per entry and the
+/// outcome is reported per file. The archive is written to disk first and imported off the async
+/// runtime.
+#[utoipa::path(
+    post,
+    path = "/v2/trekkie/import",
+    params(ImportQuery),
+    responses(
+        (status = 200, description = "archive was processed, see the results per file", body = ImportSummary),
+        (status = 400, description = "upload is no zip archive or has no valid manifest.json"),
+        (status = 413, description = "upload or the decompressed files together exceed the maximum upload size"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[post("/trekkie/import")]
+pub async fn bulk_import(
+    pool: web::Data<DbPool>,
+    chemo: web::Data<ChemoForwarder>,
+    ingest_config: web::Data<IngestConfig>,
+    regions: web::Data<Regions>,
+    user: Credentials,
+    mut payload: Multipart,
+    query: web::Query<ImportQuery>,
+    req: HttpRequest,
+) -> Result<web::Json<ImportSummary>, ServerError> {
+    check_content_length(&req, ingest_config.max_upload_size)?;
+
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+
+    // the archive is the first field of the multipart upload
+    let mut field = match payload.try_next().await {
+        Ok(Some(field)) => field,
+        Ok(None) => {
+            return Err(ServerError::InvalidData(
+                "upload contains no archive".to_string(),
+            ))
+        }
+        Err(e) => {
+            warn!("upload stream broke off {}", e);
+            return Err(ServerError::InvalidData(format!(
+                "broken multipart stream: {}",
+                e
+            )));
+        }
+    };
+    let mut received: u64 = 0;
+    let file = spool_field(&mut field, &mut received, ingest_config.max_upload_size).await?;
+
+    let forward = query.forward.unwrap_or(chemo.forward_uploads());
+    let owner = user_session.user.id;
+    let import_config = ingest_config.clone();
+    let import_regions = regions.clone();
+    let (results, replays) = web::block(move || {
+        import_archive(
+            file,
+            owner,
+            forward,
+            &import_config,
+            &import_regions,
+            &mut database_connection,
+        )
+    })
+    .await
+    .map_err(|e| {
+        error!("importing the archive panicked {:?}", e);
+        ServerError::InternalError
+    })??;
+
+    // replays are spawned on the async runtime, so they are started after the import
+    for points in replays {
+        if !chemo.replay(points) {
+            warn!("cannot forward imported track, no chemo grpc host configured");
+        }
+    }
+
+    Ok(web::Json(ImportSummary { results }))
+}
@@ -0,0 +1,348 @@
+use crate::chemo::{grpc_point, ChemoForwarder};
+use crate::config::Regions;
+use crate::ingest::{ingest_points, IngestConfig, PointRef};
+use crate::lifecycle::{run_state, RunState};
+use crate::routes::run::{fetch_run, finish_run, SubmitGpsPoint};
+use crate::routes::{
+    user::{fetch_user, Credentials},
+    ServerError,
+};
+use crate::DbPool;
+
+use tlms::trekkie::TrekkieRun;
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use diesel::PgConnection;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use std::time::{Duration, Instant};
+
+/// how often a ping is send to the client
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// after this time without any message from the client the socket is closed
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Messages the stasi app sends over the live socket
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveClientMessage {
+    /// a new gps point, which is acknowledged with the given sequence number. Resending a point
+    /// after a reconnect is safe, it is only stored once.
+    Point {
+        sequence: u64,
+        point: SubmitGpsPoint,
+    },
+    /// terminates the run and closes the socket
+    Finish,
+}
+
+/// Messages trekkie sends back over the live socket
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveServerMessage {
+    /// the point with this sequence number was stored, duplicate is set if it was already known.
+    /// Problems are listed if the point failed the validation but was stored flagged.
+    Ack {
+        sequence: u64,
+        duplicate: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        problems: Vec<String>,
+    },
+    /// the point with this sequence number failed the validation and was not stored, it should
+    /// not be resent
+    Rejected {
+        sequence: u64,
+        problems: Vec<String>,
+    },
+    /// the message could not be processed
+    Error {
+        sequence: Option<u64>,
+        message: String,
+    },
+    /// the run was terminated, its state is empty if it never received a point. Also sent when
+    /// the run was terminated or aborted elsewhere, no more points are taken afterwards.
+    Finished { state: RunState },
+}
+
+/// Websocket session which belongs to exactly one unfinished trekkie run
+pub struct LiveRunSocket {
+    pool: web::Data<DbPool>,
+    chemo: web::Data<ChemoForwarder>,
+    ingest_config: web::Data<IngestConfig>,
+    regions: web::Data<Regions>,
+    trekkie_run: TrekkieRun,
+    last_heartbeat: Instant,
+    finished: bool,
+}
+
+impl LiveRunSocket {
+    fn send(&self, message: LiveServerMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match serde_json::to_string(&message) {
+            Ok(text) => ctx.text(text),
+            Err(e) => error!("cannot serialize live socket message {:?}", e),
+        }
+    }
+
+    fn error(&self, sequence: Option<u64>, message: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        self.send(
+            LiveServerMessage::Error {
+                sequence,
+                message: message.to_string(),
+            },
+            ctx,
+        );
+    }
+
+    /// Reports the state of a run which was terminated elsewhere, e.g. by the delete endpoint or
+    /// the reaper, and stops taking points.
+    fn report_terminated(
+        &mut self,
+        sequence: Option<u64>,
+        database_connection: &mut PgConnection,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.finished = true;
+
+        match fetch_run(self.trekkie_run.id, database_connection)
+            .and_then(|trekkie_run| run_state(&trekkie_run, database_connection))
+        {
+            Ok((state, _)) => self.send(LiveServerMessage::Finished { state }, ctx),
+            Err(e) => self.error(sequence, &e.to_string(), ctx),
+        }
+    }
+
+    /// validates and stores the point, forwards it to chemo and acknowledges it
+    fn handle_point(
+        &mut self,
+        sequence: u64,
+        point: SubmitGpsPoint,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if self.finished {
+            self.error(Some(sequence), "run is already finished", ctx);
+            return;
+        }
+
+        let mut database_connection = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("cannot get connection from connection pool {:?}", e);
+                self.error(Some(sequence), "internal error", ctx);
+                return;
+            }
+        };
+
+        let point_ref = PointRef {
+            index: 0,
+            sequence: Some(sequence),
+        };
+        let (mut report, stored) = match ingest_points(
+            self.trekkie_run.id,
+            vec![(point.to_insert(self.trekkie_run.id), point_ref)],
+            &self.ingest_config.validation,
+            self.regions.geofence(self.trekkie_run.region),
+            &mut database_connection,
+        ) {
+            Ok(result) => result,
+            Err(ServerError::Conflict) => {
+                self.report_terminated(Some(sequence), &mut database_connection, ctx);
+                return;
+            }
+            Err(e) => {
+                self.error(Some(sequence), &e.to_string(), ctx);
+                return;
+            }
+        };
+
+        let invalid = report.invalid.pop();
+        if let Some(invalid) = invalid.as_ref().filter(|invalid| !invalid.stored) {
+            self.send(
+                LiveServerMessage::Rejected {
+                    sequence,
+                    problems: invalid.problems.clone(),
+                },
+                ctx,
+            );
+            return;
+        }
+
+        self.chemo.enqueue(
+            stored
+                .iter()
+                .map(|(time, lat, lon)| grpc_point(&self.trekkie_run, *time, *lat, *lon))
+                .collect(),
+        );
+
+        self.send(
+            LiveServerMessage::Ack {
+                sequence,
+                duplicate: stored.is_empty(),
+                problems: invalid.map(|invalid| invalid.problems).unwrap_or_default(),
+            },
+            ctx,
+        );
+    }
+
+    /// terminates the run exactly like the delete endpoint, a run which was terminated elsewhere
+    /// meanwhile is not finished again but its state is reported
+    fn handle_finish(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.finished {
+            return;
+        }
+
+        let mut database_connection = match self.pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("cannot get connection from connection pool {:?}", e);
+                self.error(None, "internal error", ctx);
+                return;
+            }
+        };
+
+        match finish_run(
+            self.trekkie_run.id,
+            self.ingest_config.validation.max_speed,
+            &mut database_connection,
+        ) {
+            Ok(state) => {
+                info!(
+                    "live run {} was finished over websocket",
+                    self.trekkie_run.id
+                );
+                self.finished = true;
+                self.send(LiveServerMessage::Finished { state }, ctx);
+            }
+            Err(ServerError::Conflict) => {
+                self.report_terminated(None, &mut database_connection, ctx);
+            }
+            Err(e) => {
+                self.error(None, &e.to_string(), ctx);
+            }
+        }
+    }
+}
+
+impl Actor for LiveRunSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |socket, ctx| {
+            if Instant::now().duration_since(socket.last_heartbeat) > CLIENT_TIMEOUT {
+                warn!(
+                    "live socket of run {} timed out, closing it",
+                    socket.trekkie_run.id
+                );
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LiveRunSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        self.last_heartbeat = Instant::now();
+
+        match msg {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Pong(_)) => {}
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<LiveClientMessage>(&text) {
+                Ok(LiveClientMessage::Point { sequence, point }) => {
+                    self.handle_point(sequence, point, ctx)
+                }
+                Ok(LiveClientMessage::Finish) => {
+                    self.handle_finish(ctx);
+                    ctx.close(Some(ws::CloseCode::Normal.into()));
+                    ctx.stop();
+                }
+                Err(e) => {
+                    warn!("cannot parse live socket message {:?}", e);
+                    self.error(None, "cannot parse message", ctx);
+                }
+            },
+            Ok(ws::Message::Binary(_)) => {
+                self.error(None, "binary messages are not supported", ctx);
+            }
+            Ok(ws::Message::Close(reason)) => {
+                self.handle_finish(ctx);
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(ws::Message::Continuation(_)) | Ok(ws::Message::Nop) => {}
+            Err(e) => {
+                warn!("protocol error on live socket {:?}", e);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/// Opens a websocket for streaming live gps points of an unfinished run. Every point frame is
+/// acknowledged with its sequence number, a finish or close frame terminates the run.
+#[utoipa::path(
+    get,
+    path = "/v2/trekkie/{id}/live/ws",
+    responses(
+        (status = 101, description = "websocket was opened"),
+        (status = 400, description = "request is not a websocket handshake"),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is already finished, aborted or empty"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/trekkie/{id}/live/ws")]
+pub async fn live_socket(
+    pool: web::Data<DbPool>,
+    chemo: web::Data<ChemoForwarder>,
+    ingest_config: web::Data<IngestConfig>,
+    regions: web::Data<Regions>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    let (state, _) = run_state(&trekkie_run, &mut database_connection)?;
+    if state.is_terminal() {
+        return Err(ServerError::Conflict);
+    }
+
+    let socket = LiveRunSocket {
+        pool: pool.clone(),
+        chemo: chemo.clone(),
+        ingest_config: ingest_config.clone(),
+        regions: regions.clone(),
+        trekkie_run,
+        last_heartbeat: Instant::now(),
+        finished: false,
+    };
+
+    ws::start(socket, &req, stream).map_err(|e| {
+        error!("cannot open live websocket {:?}", e);
+        ServerError::BadClientData
+    })
+}
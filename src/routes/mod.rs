@@ -1,10 +1,19 @@
+pub mod correlation;
+pub mod import;
+pub mod live;
+pub mod region;
 pub mod run;
+pub mod status;
+pub mod token;
+pub mod track;
 pub mod user;
 
 use actix_web::{
+    dev::ServiceResponse,
     error,
-    http::{header::ContentType, StatusCode},
-    HttpResponse,
+    http::{header, StatusCode},
+    middleware::ErrorHandlerResponse,
+    HttpRequest, HttpResponse,
 };
 use derive_more::{Display, Error};
 use serde::{Deserialize, Serialize};
@@ -17,6 +26,19 @@ pub struct Response {
     success: bool,
 }
 
+/// Error body which is returned by every failing request
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// stable machine readable error code
+    #[schema(example = "not_found")]
+    pub code: String,
+    /// human readable description of the error
+    #[schema(example = "Not Found")]
+    pub message: String,
+    /// additional information about what went wrong if available
+    pub details: Option<String>,
+}
+
 #[derive(Debug, Display, Error)]
 pub enum ServerError {
     #[display(fmt = "Internal Error")]
@@ -25,49 +47,175 @@ pub enum ServerError {
     #[display(fmt = "Bad Request")]
     BadClientData,
 
+    #[display(fmt = "Invalid Data")]
+    InvalidData(#[error(not(source))] String),
+
+    #[display(fmt = "Unauthorized")]
+    Unauthorized,
+
     #[display(fmt = "Forbidden")]
     Forbidden,
 
+    #[display(fmt = "Not Found")]
+    NotFound,
+
     #[display(fmt = "Conflict")]
     Conflict,
+
+    #[display(fmt = "Payload Too Large")]
+    PayloadTooLarge,
+}
+
+impl ServerError {
+    /// stable error code which is returned in the error body
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ServerError::InternalError => "internal_error",
+            ServerError::BadClientData => "bad_request",
+            ServerError::InvalidData(_) => "invalid_data",
+            ServerError::Unauthorized => "unauthorized",
+            ServerError::Forbidden => "forbidden",
+            ServerError::NotFound => "not_found",
+            ServerError::Conflict => "conflict",
+            ServerError::PayloadTooLarge => "payload_too_large",
+        }
+    }
+
+    fn details(&self) -> Option<String> {
+        match self {
+            ServerError::InvalidData(details) => Some(details.clone()),
+            _ => None,
+        }
+    }
 }
 
 impl error::ResponseError for ServerError {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            code: self.code().to_string(),
+            message: self.to_string(),
+            details: self.details(),
+        })
     }
 
     fn status_code(&self) -> StatusCode {
         match *self {
             ServerError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
             ServerError::BadClientData => StatusCode::BAD_REQUEST,
+            ServerError::InvalidData(_) => StatusCode::BAD_REQUEST,
+            ServerError::Unauthorized => StatusCode::UNAUTHORIZED,
             ServerError::Forbidden => StatusCode::FORBIDDEN,
+            ServerError::NotFound => StatusCode::NOT_FOUND,
             ServerError::Conflict => StatusCode::CONFLICT,
+            ServerError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
 }
 
+/// turns errors of the json, query and path extractors into the json error body
+pub fn extractor_error<E: std::fmt::Display>(err: E, _req: &HttpRequest) -> error::Error {
+    ServerError::InvalidData(err.to_string()).into()
+}
+
+/// The identity extractor answers missing sessions with an empty 401, this replaces the body with
+/// the json error body.
+pub fn unauthorized_handler<B>(
+    res: ServiceResponse<B>,
+) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("application/json"))
+        .unwrap_or(false);
+
+    if is_json {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+
+    let (req, _) = res.into_parts();
+    let response = error::ResponseError::error_response(&ServerError::Unauthorized);
+
+    Ok(ErrorHandlerResponse::Response(
+        ServiceResponse::new(req, response).map_into_right_body(),
+    ))
+}
+
 #[derive(OpenApi)]
 #[openapi(
+    info(
+        description = "Every failing request returns an `ErrorResponse` json body with a stable \
+        machine readable `code` (internal_error, bad_request, invalid_data, unauthorized, \
+        forbidden, not_found, conflict, payload_too_large), a human readable `message` and optional `details`."
+    ),
     paths(
         run::travel_submit_run_v1,
         run::travel_submit_run_v2,
         run::travel_file_upload,
+        import::bulk_import,
         run::submit_gps_live,
+        run::submit_gps_live_batch,
+        live::live_socket,
         run::terminate_run,
+        run::abort_run,
+        run::list_runs,
+        run::get_run,
+        run::run_stats,
+        track::export_track,
+        track::replay_track,
+        track::clean_track,
+        correlation::correlate,
+        correlation::get_correlation,
+        region::reporting_points,
+        status::status,
         user::user_login,
-        user::user_create
+        user::user_create,
+        token::token_create,
+        token::token_list,
+        token::token_revoke
     ),
     components(schemas(
         Response,
+        ErrorResponse,
         user::UserCreation,
         user::UserLogin,
+        token::TokenCreation,
+        token::CreatedToken,
+        token::TokenInfo,
         run::SubmitTravelV1,
         run::SubmitTravelV2,
         run::SubmitGpsPoint,
-        run::SubmitRun
+        run::SubmitRun,
+        crate::ingest::IngestReport,
+        crate::ingest::PointRef,
+        crate::ingest::SkippedPoint,
+        crate::ingest::InvalidPoint,
+        live::LiveClientMessage,
+        live::LiveServerMessage,
+        run::ListRunsQuery,
+        run::RunInfo,
+        run::RunList,
+        run::RunDetail,
+        run::AbortRun,
+        run::RunStatus,
+        crate::lifecycle::RunState,
+        status::ServerStatus,
+        crate::chemo::ChemoStatus,
+        track::TrackQuery,
+        track::ReplayResponse,
+        crate::processing::CleaningReport,
+        crate::simplification::Simplification,
+        crate::statistics::RunStatistics,
+        crate::statistics::IntervalBucket,
+        correlation::CorrelationResult,
+        correlation::CorrelatedTelegram,
+        run::UploadQuery,
+        crate::formats::TrackFormat,
+        crate::formats::ImportFormat,
+        import::ImportManifestEntry,
+        import::ImportQuery,
+        import::ImportResult,
+        import::ImportSummary
     ))
 )]
 pub struct ApiDoc;
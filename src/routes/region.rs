@@ -0,0 +1,81 @@
+use crate::models::ReportingPoint;
+use crate::routes::ServerError;
+use crate::DbPool;
+
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use log::error;
+use serde_json::{json, Value};
+
+/// Returns the aggregated positions of the reporting points of a region as GeoJSON
+/// FeatureCollection. Every reporting point is a Point feature whose properties carry the
+/// reporting point number, the amount of samples, rejected outliers and runs it is based on and
+/// the spread of the samples in meters. The positions contain no personal data, so no session is
+/// required.
+#[utoipa::path(
+    get,
+    path = "/v2/regions/{region}/reporting_points",
+    responses(
+        (status = 200, description = "GeoJSON FeatureCollection of the reporting points"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/regions/{region}/reporting_points")]
+pub async fn reporting_points(
+    pool: web::Data<DbPool>,
+    path: web::Path<(i64,)>,
+    _req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    use crate::schema::trekkie_reporting_points::dsl::{
+        region, reporting_point, trekkie_reporting_points,
+    };
+
+    let points = trekkie_reporting_points
+        .filter(region.eq(path.0))
+        .order(reporting_point.asc())
+        .load::<ReportingPoint>(&mut database_connection)
+        .map_err(|e| {
+            error!("database error while loading reporting points {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    let features: Vec<Value> = points
+        .iter()
+        .map(|point| {
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [point.lon, point.lat],
+                },
+                "properties": {
+                    "reporting_point": point.reporting_point,
+                    "samples": point.samples,
+                    "outliers": point.outliers,
+                    "runs": point.runs,
+                    "spread": point.spread,
+                    "updated_at": point.updated_at,
+                },
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/geo+json"))
+        .body(
+            json!({
+                "type": "FeatureCollection",
+                "features": features,
+            })
+            .to_string(),
+        ))
+}
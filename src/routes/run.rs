@@ -1,30 +1,104 @@
-use crate::routes::{user::fetch_user, ServerError};
+use crate::chemo::{grpc_point, ChemoForwarder};
+use crate::config::{local_to_utc, offset_from_minutes, Geofence, Regions};
+use crate::correlation::correlate_run;
+use crate::formats::{compression::SizeBudget, import, ImportFormat, UploadedFile};
+use crate::ingest::{
+    ingest_points, ChunkedInsert, IngestConfig, IngestReport, PointRef, StoredPoint,
+};
+use crate::lifecycle::{lock_state, run_state, set_state, RunState};
+use crate::models::{RunActivity, V1NormalizedRun};
+use crate::processing::clean_run;
+use crate::routes::{
+    user::{fetch_user, Credentials},
+    ServerError,
+};
+use crate::statistics::{fetch_statistics, update_statistics, RunStatistics};
 use crate::DbPool;
 
 use tlms::grpc::GrpcGpsPoint;
-use tlms::locations::gps::{GpsPoint, InsertGpsPoint};
+use tlms::locations::gps::InsertGpsPoint;
 use tlms::trekkie::TrekkieRun;
 
-use actix_identity::Identity;
-use actix_multipart::Multipart;
-use actix_web::{delete, post, web, HttpRequest, HttpResponse};
-use chrono::{DateTime, Duration, TimeZone, Utc};
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use actix_multipart::{Field, Multipart};
+use actix_web::{delete, get, http::header, post, web, HttpRequest};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::pg::Pg;
+use diesel::{Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use futures::{StreamExt, TryStreamExt};
-use gpx;
-use log::{error, warn};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-/// This struct is send to trekkie to declare a trekkie run
+use std::fs::File;
+use std::io::{Seek, Write};
+
+/// default amount of runs returned by the list endpoint
+const DEFAULT_RUN_LIMIT: i64 = 50;
+
+/// maximum amount of runs returned by the list endpoint
+const MAX_RUN_LIMIT: i64 = 500;
+
+/// app commit v1 runs are stored with
+pub const V1_APP_COMMIT: &str = "0000000000000000000000000000000000000000";
+
+/// app name v1 runs are stored with
+pub const V1_APP_NAME: &str = "stasi";
+
+/// maximum amount of gps points accepted in one batch, keeps the insert below the postgres
+/// parameter limit
+pub const MAX_BATCH_SIZE: usize = 5000;
+
+/// maximum amount of characters of the reason a run is aborted with
+const MAX_ABORT_REASON_LENGTH: usize = 500;
+
+/// This struct is send to trekkie to declare a trekkie run. Old stasi versions send their local
+/// wall clock time marked as utc, so times without offset are interpreted in `timezone` or
+/// `utc_offset` and fall back to the configured timezone of the region. Times with an explicit
+/// offset are taken as they are.
 #[derive(Serialize, Deserialize, ToSchema)]
 pub struct SubmitTravelV1 {
-    pub start: DateTime<Utc>,
-    pub stop: DateTime<Utc>,
+    pub start: DateTime<FixedOffset>,
+    pub stop: DateTime<FixedOffset>,
     pub line: i32,
     pub run: i32,
     pub region: i64,
+    /// IANA timezone of start and stop e.g. Europe/Berlin
+    pub timezone: Option<String>,
+    /// offset of start and stop to utc in minutes
+    pub utc_offset: Option<i32>,
+}
+
+impl SubmitTravelV1 {
+    /// converts start or stop of this submission into utc
+    fn time_to_utc(
+        &self,
+        time: &DateTime<FixedOffset>,
+        regions: &Regions,
+    ) -> Result<NaiveDateTime, ServerError> {
+        if time.offset().local_minus_utc() != 0 {
+            return Ok(time.naive_utc());
+        }
+
+        let converted = if let Some(minutes) = self.utc_offset {
+            let offset = offset_from_minutes(minutes).ok_or_else(|| {
+                ServerError::InvalidData(format!("invalid utc offset {} minutes", minutes))
+            })?;
+            local_to_utc(&offset, time.naive_local())
+        } else if let Some(name) = &self.timezone {
+            let timezone: Tz = name
+                .parse()
+                .map_err(|_| ServerError::InvalidData(format!("unknown timezone {}", name)))?;
+            local_to_utc(&timezone, time.naive_local())
+        } else {
+            local_to_utc(&regions.timezone(self.region), time.naive_local())
+        };
+
+        converted
+            .map(|value| value.naive_utc())
+            .ok_or_else(|| ServerError::InvalidData(format!("{} does not exist in timezone", time)))
+    }
 }
 
 /// This struct is send to trekkie to declare a trekkie run
@@ -48,6 +122,37 @@ pub struct SubmitGpsPoint {
     pub vertical_accuracy: Option<f64>,
     pub bearing: Option<f64>,
     pub speed: Option<f64>,
+    /// optional client side sequence number which is echoed back in the ingest report
+    #[serde(default)]
+    pub sequence: Option<u64>,
+}
+
+impl SubmitGpsPoint {
+    /// converts the submitted point into a database row for the given trekkie run
+    pub fn to_insert(&self, run_id: Uuid) -> InsertGpsPoint {
+        InsertGpsPoint {
+            id: None,
+            trekkie_run: run_id,
+            timestamp: self.timestamp.naive_utc(),
+            lat: self.lat,
+            lon: self.lon,
+            elevation: self.elevation,
+            accuracy: self.accuracy,
+            bearing: self.bearing,
+            speed: self.speed,
+            vertical_accuracy: self.vertical_accuracy,
+        }
+    }
+}
+
+/// Query parameters of the track file upload
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadQuery {
+    /// format of the uploaded files, detected from content type, file name and content if missing
+    pub format: Option<ImportFormat>,
+    /// replay the uploaded points to chemo, defaults to the server configuration
+    pub forward: Option<bool>,
 }
 
 /// This model is returned after uploading a file. It returns the travel id, which is used for
@@ -57,6 +162,434 @@ pub struct SubmitRun {
     pub trekkie_run: Uuid,
 }
 
+/// Reason the app gives when cancelling a run
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AbortRun {
+    /// at most 500 characters, e.g. "started by accident"
+    pub reason: String,
+}
+
+/// State of a run after it was terminated or aborted
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RunStatus {
+    pub trekkie_run: Uuid,
+    pub state: RunState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aborted_reason: Option<String>,
+}
+
+/// Query parameters for filtering and paginating the list of trekkie runs
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListRunsQuery {
+    /// amount of runs that are skipped
+    pub offset: Option<i64>,
+    /// amount of runs that are returned, at most 500
+    pub limit: Option<i64>,
+    pub line: Option<i32>,
+    pub run: Option<i32>,
+    pub region: Option<i64>,
+    pub finished: Option<bool>,
+    pub correlated: Option<bool>,
+    /// only runs which end after this point in time
+    pub from: Option<DateTime<Utc>>,
+    /// only runs which start before this point in time
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Metadata of a single trekkie run
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RunInfo {
+    pub id: Uuid,
+    pub start_time: NaiveDateTime,
+    pub end_time: NaiveDateTime,
+    pub line: i32,
+    pub run: i32,
+    pub region: i64,
+    pub owner: Uuid,
+    pub finished: bool,
+    pub correlated: bool,
+    pub app_commit: String,
+    pub app_name: String,
+}
+
+impl From<TrekkieRun> for RunInfo {
+    fn from(trekkie_run: TrekkieRun) -> RunInfo {
+        RunInfo {
+            id: trekkie_run.id,
+            start_time: trekkie_run.start_time,
+            end_time: trekkie_run.end_time,
+            line: trekkie_run.line,
+            run: trekkie_run.run,
+            region: trekkie_run.region,
+            owner: trekkie_run.owner,
+            finished: trekkie_run.finished,
+            correlated: trekkie_run.correlated,
+            app_commit: trekkie_run.app_commit,
+            app_name: trekkie_run.app_name,
+        }
+    }
+}
+
+/// Paginated list of trekkie runs
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RunList {
+    /// total amount of runs matching the filter
+    pub count: i64,
+    pub offset: i64,
+    pub limit: i64,
+    pub runs: Vec<RunInfo>,
+}
+
+/// Run metadata together with the amount of recorded gps points
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RunDetail {
+    #[serde(flatten)]
+    pub run: RunInfo,
+    pub state: RunState,
+    /// reason the app gave when aborting the run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aborted_reason: Option<String>,
+    pub gps_points: i64,
+    /// percentage of the gps points outside of the geofence of the run's region, missing if the
+    /// region has no geofence or the run has no points
+    pub outside_region: Option<f64>,
+}
+
+/// percentage of the stored points of the run which lie outside of the geofence
+fn outside_region_percentage(
+    run_id: Uuid,
+    geofence: &Geofence,
+    database_connection: &mut PgConnection,
+) -> Result<Option<f64>, ServerError> {
+    use tlms::schema::gps_points::dsl::{gps_points, lat, lon, trekkie_run};
+
+    let positions = gps_points
+        .filter(trekkie_run.eq(run_id))
+        .select((lat, lon))
+        .load::<(f64, f64)>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading gps points {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    if positions.is_empty() {
+        return Ok(None);
+    }
+
+    let outside = positions
+        .iter()
+        .filter(|(point_lat, point_lon)| !geofence.contains(*point_lat, *point_lon))
+        .count();
+
+    Ok(Some(outside as f64 * 100.0 / positions.len() as f64))
+}
+
+/// looks up the trekkie run with the given id
+pub(crate) fn fetch_run(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<TrekkieRun, ServerError> {
+    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
+    use tlms::schema::trekkie_runs::id as trekkie_id;
+
+    match trekkie_runs
+        .filter(trekkie_id.eq(run_id))
+        .first::<TrekkieRun>(database_connection)
+    {
+        Ok(found_run) => Ok(found_run),
+        Err(diesel::result::Error::NotFound) => Err(ServerError::NotFound),
+        Err(e) => {
+            error!("database error while listing trekkie_runs {:?}", e);
+            Err(ServerError::InternalError)
+        }
+    }
+}
+
+/// builds the trekkie run query for the given filter, if owner is set only runs of this user are
+/// selected
+fn filtered_runs<'a>(
+    filter: &ListRunsQuery,
+    run_owner: Option<Uuid>,
+) -> tlms::schema::trekkie_runs::BoxedQuery<'a, Pg> {
+    use tlms::schema::trekkie_runs::dsl::*;
+
+    let mut query = trekkie_runs.into_boxed();
+
+    if let Some(value) = run_owner {
+        query = query.filter(owner.eq(value));
+    }
+    if let Some(value) = filter.line {
+        query = query.filter(line.eq(value));
+    }
+    if let Some(value) = filter.run {
+        query = query.filter(run.eq(value));
+    }
+    if let Some(value) = filter.region {
+        query = query.filter(region.eq(value));
+    }
+    if let Some(value) = filter.finished {
+        query = query.filter(finished.eq(value));
+    }
+    if let Some(value) = filter.correlated {
+        query = query.filter(correlated.eq(value));
+    }
+    if let Some(value) = filter.from {
+        query = query.filter(end_time.ge(value.naive_utc()));
+    }
+    if let Some(value) = filter.to {
+        query = query.filter(start_time.le(value.naive_utc()));
+    }
+
+    query
+}
+
+/// Lists the trekkie runs of the authenticated user, admins can see the runs of all users.
+#[utoipa::path(
+    get,
+    path = "/v2/trekkie",
+    params(ListRunsQuery),
+    responses(
+        (status = 200, description = "list of trekkie runs", body = RunList),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/trekkie")]
+pub async fn list_runs(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    filter: web::Query<ListRunsQuery>,
+    _req: HttpRequest,
+) -> Result<web::Json<RunList>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+
+    let run_owner = if user_session.is_admin() {
+        None
+    } else {
+        Some(user_session.user.id)
+    };
+
+    let offset = filter.offset.unwrap_or(0).max(0);
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_RUN_LIMIT)
+        .clamp(1, MAX_RUN_LIMIT);
+
+    let count = match filtered_runs(&filter, run_owner)
+        .count()
+        .get_result::<i64>(&mut database_connection)
+    {
+        Ok(value) => value,
+        Err(e) => {
+            error!("database error while counting trekkie_runs {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    use tlms::schema::trekkie_runs::start_time;
+
+    let runs = match filtered_runs(&filter, run_owner)
+        .order(start_time.desc())
+        .offset(offset)
+        .limit(limit)
+        .load::<TrekkieRun>(&mut database_connection)
+    {
+        Ok(value) => value,
+        Err(e) => {
+            error!("database error while listing trekkie_runs {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    Ok(web::Json(RunList {
+        count,
+        offset,
+        limit,
+        runs: runs.into_iter().map(RunInfo::from).collect(),
+    }))
+}
+
+/// Returns the metadata, the state, the amount of gps points and the share of points outside of
+/// the region of a single trekkie run
+#[utoipa::path(
+    get,
+    path = "/v2/trekkie/{id}",
+    responses(
+        (status = 200, description = "trekkie run", body = RunDetail),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/trekkie/{id}")]
+pub async fn get_run(
+    pool: web::Data<DbPool>,
+    regions: web::Data<Regions>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<RunDetail>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    use tlms::schema::gps_points::dsl::gps_points;
+    use tlms::schema::gps_points::trekkie_run as gps_trekkie_run;
+
+    let point_count = match gps_points
+        .filter(gps_trekkie_run.eq(path.0))
+        .count()
+        .get_result::<i64>(&mut database_connection)
+    {
+        Ok(value) => value,
+        Err(e) => {
+            error!("database error while counting gps points {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let outside_region = match regions.geofence(trekkie_run.region) {
+        Some(geofence) => outside_region_percentage(path.0, geofence, &mut database_connection)?,
+        None => None,
+    };
+
+    let (state, aborted_reason) = run_state(&trekkie_run, &mut database_connection)?;
+
+    Ok(web::Json(RunDetail {
+        run: RunInfo::from(trekkie_run),
+        state,
+        aborted_reason,
+        gps_points: point_count,
+        outside_region,
+    }))
+}
+
+/// Returns distance, duration, speeds, sampling intervals, gaps and accuracy of a run. They are
+/// computed when the run is finished, runs finished before get them on the first request.
+#[utoipa::path(
+    get,
+    path = "/v2/trekkie/{id}/stats",
+    responses(
+        (status = 200, description = "statistics of the run", body = RunStatistics),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/trekkie/{id}/stats")]
+pub async fn run_stats(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<RunStatistics>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    let stats = fetch_statistics(&trekkie_run, &mut database_connection)?;
+
+    Ok(web::Json(RunStatistics::from(stats)))
+}
+
+/// creates a new unfinished run owned by the given user
+pub(crate) fn create_run(
+    measurement: &SubmitTravelV2,
+    owner: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<Uuid, ServerError> {
+    use crate::schema::trekkie_run_activity::dsl::trekkie_run_activity;
+    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
+    let run_id = Uuid::new_v4();
+    match database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        diesel::insert_into(trekkie_runs)
+            .values(&TrekkieRun {
+                id: run_id,
+                start_time: Utc.timestamp_millis_opt(0).unwrap().naive_utc(),
+                end_time: Utc.timestamp_millis_opt(0).unwrap().naive_utc(),
+                line: measurement.line,
+                run: measurement.run,
+                region: measurement.region,
+                owner,
+                finished: false,
+                correlated: false,
+                app_commit: measurement.app_commit.clone(),
+                app_name: measurement.app_name.clone(),
+            })
+            .execute(conn)?;
+        diesel::insert_into(trekkie_run_activity)
+            .values(&RunActivity {
+                trekkie_run: run_id,
+                created_at: Utc::now().naive_utc(),
+                last_point_at: None,
+                state: RunState::Created.as_str().to_string(),
+                aborted_reason: None,
+            })
+            .execute(conn)
+    }) {
+        Ok(_result) => Ok(run_id),
+        Err(e) => {
+            error!("while trying to insert trekkie run {:?}", e);
+            Err(ServerError::InternalError)
+        }
+    }
+}
+
+/// removes a run together with its gps points
+pub(crate) fn delete_run(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<(), ServerError> {
+    use tlms::schema::gps_points::dsl::gps_points;
+    use tlms::schema::gps_points::trekkie_run;
+    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
+    use tlms::schema::trekkie_runs::id as trekkie_id;
+
+    database_connection
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::delete(gps_points.filter(trekkie_run.eq(run_id))).execute(conn)?;
+            diesel::delete(trekkie_runs.filter(trekkie_id.eq(run_id))).execute(conn)?;
+            Ok(())
+        })
+        .map_err(|e| {
+            error!("cannot delete trekkie run {} {:?}", run_id, e);
+            ServerError::InternalError
+        })
+}
+
 /// This endpoint accepts measurement intervals that belong to the previously submitted gpx
 /// file.
 #[utoipa::path(
@@ -70,7 +603,8 @@ pub struct SubmitRun {
 #[post("/trekkie")]
 pub async fn travel_submit_run_v1(
     pool: web::Data<DbPool>,
-    user: Identity,
+    regions: web::Data<Regions>,
+    user: Credentials,
     measurement: web::Json<SubmitTravelV1>,
     _req: HttpRequest,
 ) -> Result<web::Json<SubmitRun>, ServerError> {
@@ -83,24 +617,40 @@ pub async fn travel_submit_run_v1(
         }
     };
 
+    let user_session = fetch_user(user, &mut database_connection)?;
+
+    let start = measurement.time_to_utc(&measurement.start, &regions)?;
+    let stop = measurement.time_to_utc(&measurement.stop, &regions)?;
+
+    use crate::schema::trekkie_v1_normalized_runs::dsl::trekkie_v1_normalized_runs;
     use tlms::schema::trekkie_runs::dsl::trekkie_runs;
     let run_id = Uuid::new_v4();
-    match diesel::insert_into(trekkie_runs)
-        .values(&TrekkieRun {
-            id: run_id,
-            start_time: measurement.start.naive_utc() - Duration::hours(2),
-            end_time: measurement.stop.naive_utc() - Duration::hours(2),
-            line: measurement.line,
-            run: measurement.run,
-            region: measurement.region,
-            owner: Uuid::parse_str(&user.id().unwrap()).unwrap(),
-            finished: true,
-            correlated: false,
-            app_commit: "0000000000000000000000000000000000000000".to_string(),
-            app_name: "stasi".to_string(),
-        })
-        .execute(&mut database_connection)
-    {
+    match database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        diesel::insert_into(trekkie_runs)
+            .values(&TrekkieRun {
+                id: run_id,
+                start_time: start,
+                end_time: stop,
+                line: measurement.line,
+                run: measurement.run,
+                region: measurement.region,
+                owner: user_session.user.id,
+                finished: true,
+                correlated: false,
+                app_commit: V1_APP_COMMIT.to_string(),
+                app_name: V1_APP_NAME.to_string(),
+            })
+            .execute(conn)?;
+
+        // these times are already in utc and must not be touched by --renormalize-v1-runs
+        diesel::insert_into(trekkie_v1_normalized_runs)
+            .values(&V1NormalizedRun {
+                trekkie_run: run_id,
+                shifted_by_minutes: 0,
+                normalized_at: Utc::now().naive_utc(),
+            })
+            .execute(conn)
+    }) {
         Ok(_result) => Ok(web::Json(SubmitRun {
             trekkie_run: run_id,
         })),
@@ -124,7 +674,7 @@ pub async fn travel_submit_run_v1(
 #[post("/trekkie")]
 pub async fn travel_submit_run_v2(
     pool: web::Data<DbPool>,
-    user: Identity,
+    user: Credentials,
     measurement: web::Json<SubmitTravelV2>,
     _req: HttpRequest,
 ) -> Result<web::Json<SubmitRun>, ServerError> {
@@ -137,50 +687,35 @@ pub async fn travel_submit_run_v2(
         }
     };
 
-    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
-    let run_id = Uuid::new_v4();
-    match diesel::insert_into(trekkie_runs)
-        .values(&TrekkieRun {
-            id: run_id,
-            start_time: Utc.timestamp_millis_opt(0).unwrap().naive_utc(),
-            end_time: Utc.timestamp_millis_opt(0).unwrap().naive_utc(),
-            line: measurement.line,
-            run: measurement.run,
-            region: measurement.region,
-            owner: Uuid::parse_str(&user.id().unwrap()).unwrap(),
-            finished: false,
-            correlated: false,
-            app_commit: measurement.app_commit.clone(),
-            app_name: measurement.app_name.clone(),
-        })
-        .execute(&mut database_connection)
-    {
-        Ok(_result) => Ok(web::Json(SubmitRun {
-            trekkie_run: run_id,
-        })),
-        Err(e) => {
-            error!("while trying to insert trekkie run {:?}", e);
-            Err(ServerError::InternalError)
-        }
-    }
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let run_id = create_run(&measurement, user_session.user.id, &mut database_connection)?;
+
+    Ok(web::Json(SubmitRun {
+        trekkie_run: run_id,
+    }))
 }
 
-/// this endpoint takes live gps data from stasi apps
+/// Terminates a live run. Runs with points are finished, start and end time are taken from
+/// their first and last gps point. Runs without any point end up empty.
 #[utoipa::path(
     delete,
     path = "/v2/trekkie/{id}",
     responses(
-        (status = 200, description = "run was successfully terminated",),
+        (status = 200, description = "run was finished or is empty", body = RunStatus),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is already finished, aborted or empty"),
         (status = 500, description = "postgres pool error")
     ),
 )]
 #[delete("/trekkie/{id}")]
 pub async fn terminate_run(
     pool: web::Data<DbPool>,
-    user: Identity,
+    ingest_config: web::Data<IngestConfig>,
+    user: Credentials,
     path: web::Path<(Uuid,)>,
     _req: HttpRequest,
-) -> Result<HttpResponse, ServerError> {
+) -> Result<web::Json<RunStatus>, ServerError> {
     // getting the database connection from pool
     let mut database_connection = match pool.get() {
         Ok(conn) => conn,
@@ -191,98 +726,249 @@ pub async fn terminate_run(
     };
 
     let user_session = fetch_user(user, &mut database_connection)?;
+    let this_trekkie_run = fetch_run(path.0, &mut database_connection)?;
 
-    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
-    use tlms::schema::trekkie_runs::finished;
-    use tlms::schema::trekkie_runs::id as trekkie_id;
+    if !(user_session.is_admin() || user_session.user.id == this_trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
 
-    let this_trekkie_run = match trekkie_runs
-        .filter(trekkie_id.eq(path.0))
-        .first::<TrekkieRun>(&mut database_connection)
-    {
-        Ok(found_run) => found_run,
+    // finishing again is rejected by the state check under the row lock
+    let state = finish_run(
+        path.0,
+        ingest_config.validation.max_speed,
+        &mut database_connection,
+    )?;
+
+    Ok(web::Json(RunStatus {
+        trekkie_run: path.0,
+        state,
+        aborted_reason: None,
+    }))
+}
+
+/// Cancels an accidentally started run. Its gps points are deleted and the run is kept as aborted
+/// together with the given reason, it accepts no more points afterwards.
+#[utoipa::path(
+    post,
+    path = "/v2/trekkie/{id}/abort",
+    request_body = AbortRun,
+    responses(
+        (status = 200, description = "run was aborted", body = RunStatus),
+        (status = 400, description = "reason is empty or too long"),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is already finished, aborted or empty"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[post("/trekkie/{id}/abort")]
+pub async fn abort_run(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    abort: web::Json<AbortRun>,
+    _req: HttpRequest,
+) -> Result<web::Json<RunStatus>, ServerError> {
+    let reason = abort.reason.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_ABORT_REASON_LENGTH {
+        return Err(ServerError::InvalidData(format!(
+            "reason has to contain between 1 and {} characters",
+            MAX_ABORT_REASON_LENGTH
+        )));
+    }
+
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
         Err(e) => {
-            error!("database error while listing trekkie_runs {:?}", e);
+            error!("cannot get connection from connection pool {:?}", e);
             return Err(ServerError::InternalError);
         }
     };
 
-    if !(user_session.is_admin() || user_session.user.id == this_trekkie_run.owner) {
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
         return Err(ServerError::Forbidden);
     }
 
-    if this_trekkie_run.finished {
-        error!(
-            "user tried to finish already finished trekkie run {:?}",
-            &this_trekkie_run.id
-        );
-        return Err(ServerError::Conflict);
+    use crate::schema::trekkie_run_stats::dsl::{trekkie_run as stats_run, trekkie_run_stats};
+    use tlms::schema::gps_points::dsl::{gps_points, trekkie_run as gps_run};
+    use tlms::schema::trekkie_runs::dsl::{finished, id as trekkie_id, trekkie_runs};
+
+    // cleaned points are removed together with the gps points they were derived from, the row
+    // lock keeps points from being inserted and the run from being finished meanwhile
+    let mut failure: Option<ServerError> = None;
+    let result = database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        match lock_state(path.0, conn) {
+            Ok(state) if state.is_terminal() => failure = Some(ServerError::Conflict),
+            Ok(_) => {}
+            Err(e) => failure = Some(e),
+        }
+        if failure.is_some() {
+            return Err(diesel::result::Error::RollbackTransaction);
+        }
+
+        diesel::delete(gps_points.filter(gps_run.eq(path.0))).execute(conn)?;
+        diesel::delete(trekkie_run_stats.filter(stats_run.eq(path.0))).execute(conn)?;
+        diesel::update(trekkie_runs.filter(trekkie_id.eq(path.0)))
+            .set(finished.eq(true))
+            .execute(conn)?;
+        set_state(path.0, RunState::Aborted, Some(reason), conn)
+    });
+
+    match (result, failure) {
+        (Ok(()), _) => {}
+        (Err(_), Some(e)) => return Err(e),
+        (Err(e), None) => {
+            error!("cannot abort trekkie run {} {:?}", path.0, e);
+            return Err(ServerError::InternalError);
+        }
     }
 
+    info!("run {} was aborted: {}", path.0, reason);
+
+    Ok(web::Json(RunStatus {
+        trekkie_run: path.0,
+        state: RunState::Aborted,
+        aborted_reason: Some(reason.to_string()),
+    }))
+}
+
+/// Sets start and end time of the run to the timestamps of its first and last gps point, marks
+/// it as finished, stores its cleaned track and statistics and correlates it with the telegrams of
+/// its vehicle. A run without any point is marked as finished too but ends up empty. Returns the
+/// new state of the run.
+pub(crate) fn finish_run(
+    run_id: Uuid,
+    max_speed: f64,
+    database_connection: &mut PgConnection,
+) -> Result<RunState, ServerError> {
+    let state = mark_finished(run_id, database_connection)?;
+
+    if state == RunState::Finished {
+        process_finished_run(run_id, max_speed, database_connection);
+    }
+
+    Ok(state)
+}
+
+/// Sets start and end time of the run to the timestamps of its first and last gps point and
+/// marks it as finished or, without any point, as empty. The run row is locked for the duration,
+/// so no point can be inserted meanwhile, and runs which are already finished, aborted or empty
+/// fail with a conflict. Returns the new state of the run.
+pub(crate) fn mark_finished(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<RunState, ServerError> {
+    use diesel::dsl::{max, min};
     use tlms::schema::gps_points::dsl::gps_points;
     use tlms::schema::gps_points::{timestamp, trekkie_run};
+    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
+    use tlms::schema::trekkie_runs::id as trekkie_id;
+    use tlms::schema::trekkie_runs::{end_time, finished, start_time};
 
-    let start_gps = match gps_points
-        .filter(trekkie_run.eq(path.0))
-        .order(timestamp.asc())
-        .limit(1)
-        .first::<GpsPoint>(&mut database_connection)
-    {
-        Ok(value) => value,
-        Err(e) => {
-            error!("cannot find gps points {:?}", &e);
-            return Err(ServerError::InternalError);
+    let mut failure: Option<ServerError> = None;
+    let result = database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        match lock_state(run_id, conn) {
+            Ok(state) if state.is_terminal() => failure = Some(ServerError::Conflict),
+            Ok(_) => {}
+            Err(e) => failure = Some(e),
+        }
+        if failure.is_some() {
+            return Err(diesel::result::Error::RollbackTransaction);
         }
-    };
 
-    let end_gps = match gps_points
-        .filter(trekkie_run.eq(path.0))
-        .order(timestamp.desc())
-        .limit(1)
-        .first::<GpsPoint>(&mut database_connection)
-    {
-        Ok(value) => value,
-        Err(e) => {
-            error!("cannot find gps points {:?}", &e);
-            return Err(ServerError::InternalError);
+        let bounds = gps_points
+            .filter(trekkie_run.eq(run_id))
+            .select((min(timestamp), max(timestamp)))
+            .first::<(Option<NaiveDateTime>, Option<NaiveDateTime>)>(conn)?;
+
+        match bounds {
+            (Some(first), Some(last)) => {
+                diesel::update(trekkie_runs)
+                    .filter(trekkie_id.eq(run_id))
+                    .set((finished.eq(true), start_time.eq(first), end_time.eq(last)))
+                    .execute(conn)?;
+                set_state(run_id, RunState::Finished, None, conn)?;
+                Ok(RunState::Finished)
+            }
+            // start and end time of empty runs stay unknown
+            _ => {
+                diesel::update(trekkie_runs)
+                    .filter(trekkie_id.eq(run_id))
+                    .set(finished.eq(true))
+                    .execute(conn)?;
+                set_state(run_id, RunState::Empty, None, conn)?;
+                Ok(RunState::Empty)
+            }
         }
-    };
+    });
 
-    use tlms::schema::trekkie_runs::{end_time, start_time};
-    match diesel::update(trekkie_runs)
-        .filter(trekkie_id.eq(path.0))
-        .set((
-            finished.eq(true),
-            start_time.eq(start_gps.timestamp),
-            end_time.eq(end_gps.timestamp),
-        ))
-        .execute(&mut database_connection)
-    {
-        Ok(_) => Ok(HttpResponse::Ok().finish()),
-        Err(e) => {
+    match (result, failure) {
+        (Ok(state), _) => Ok(state),
+        (Err(_), Some(e)) => Err(e),
+        (Err(e), None) => {
             error!("cannot finish this trekkie run with error {:?}", e);
             Err(ServerError::InternalError)
         }
     }
 }
 
-/// this endpoint takes live gps data from stasi apps
+/// Stores the cleaned track and the statistics of a finished run and correlates it with the
+/// telegrams of its vehicle. Failures are only logged, the raw points are kept, so every step can
+/// be repeated on demand.
+pub(crate) fn process_finished_run(
+    run_id: Uuid,
+    max_speed: f64,
+    database_connection: &mut PgConnection,
+) {
+    if clean_run(run_id, max_speed, database_connection).is_err() {
+        warn!("cannot clean the track of run {}", run_id);
+    }
+    if update_statistics(run_id, database_connection).is_err() {
+        warn!("cannot compute the statistics of run {}", run_id);
+    }
+
+    // aborted and empty runs have nothing to correlate
+    let correlation =
+        fetch_run(run_id, database_connection).and_then(|trekkie_run| {
+            match run_state(&trekkie_run, database_connection)? {
+                (RunState::Aborted | RunState::Empty, _) => Ok(None),
+                _ => correlate_run(&trekkie_run, database_connection),
+            }
+        });
+    if correlation.is_err() {
+        warn!("cannot correlate run {}", run_id);
+    }
+}
+
+/// this endpoint takes live gps data from stasi apps, the body may be sent with
+/// `Content-Encoding: gzip` or `zstd`
 #[utoipa::path(
     post,
     path = "/v2/trekkie/{id}/live",
+    request_body = SubmitGpsPoint,
     responses(
-        (status = 200, description = "travel was successfully submitted", body = SubmitRun),
+        (status = 200, description = "gps point was successfully submitted", body = IngestReport),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is already finished, aborted or empty"),
         (status = 500, description = "postgres pool error")
     ),
 )]
 #[post("/trekkie/{id}/live")]
 pub async fn submit_gps_live(
     pool: web::Data<DbPool>,
-    user: Identity,
+    chemo: web::Data<ChemoForwarder>,
+    ingest_config: web::Data<IngestConfig>,
+    regions: web::Data<Regions>,
+    user: Credentials,
     gps_point: web::Json<SubmitGpsPoint>,
     path: web::Path<(Uuid,)>,
     _req: HttpRequest,
-) -> Result<HttpResponse, ServerError> {
+) -> Result<web::Json<IngestReport>, ServerError> {
     // getting the database connection from pool
     let mut database_connection = match pool.get() {
         Ok(conn) => conn,
@@ -293,21 +979,82 @@ pub async fn submit_gps_live(
     };
 
     let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
 
-    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
-    use tlms::schema::trekkie_runs::id as trekkie_id;
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
 
-    let trekkie_run = match trekkie_runs
-        .filter(trekkie_id.eq(path.0))
-        .first::<TrekkieRun>(&mut database_connection)
-    {
-        Ok(trekkie_run) => trekkie_run,
+    if trekkie_run.finished {
+        return Err(ServerError::Conflict);
+    }
+
+    let point_ref = PointRef {
+        index: 0,
+        sequence: gps_point.sequence,
+    };
+    let (report, stored) = ingest_points(
+        path.0,
+        vec![(gps_point.to_insert(path.0), point_ref)],
+        &ingest_config.validation,
+        regions.geofence(trekkie_run.region),
+        &mut database_connection,
+    )?;
+
+    // retries of an already stored point and rejected points are not forwarded
+    chemo.enqueue(
+        stored
+            .into_iter()
+            .map(|(time, lat, lon)| grpc_point(&trekkie_run, time, lat, lon))
+            .collect(),
+    );
+
+    Ok(web::Json(report))
+}
+
+/// this endpoint takes a batch of buffered live gps points from stasi apps, the body may be sent
+/// with `Content-Encoding: gzip` or `zstd`
+#[utoipa::path(
+    post,
+    path = "/v2/trekkie/{id}/live/batch",
+    request_body = Vec<SubmitGpsPoint>,
+    responses(
+        (status = 200, description = "gps points were successfully submitted", body = IngestReport),
+        (status = 400, description = "empty or too large batch"),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is already finished, aborted or empty"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[post("/trekkie/{id}/live/batch")]
+pub async fn submit_gps_live_batch(
+    pool: web::Data<DbPool>,
+    chemo: web::Data<ChemoForwarder>,
+    ingest_config: web::Data<IngestConfig>,
+    regions: web::Data<Regions>,
+    user: Credentials,
+    batch: web::Json<Vec<SubmitGpsPoint>>,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<IngestReport>, ServerError> {
+    if batch.is_empty() || batch.len() > MAX_BATCH_SIZE {
+        error!("received gps batch with {} points", batch.len());
+        return Err(ServerError::BadClientData);
+    }
+
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
         Err(e) => {
-            error!("database error while listing trekkie_runs {:?}", e);
+            error!("cannot get connection from connection pool {:?}", e);
             return Err(ServerError::InternalError);
         }
     };
 
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
     if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
         return Err(ServerError::Forbidden);
     }
@@ -316,84 +1063,191 @@ pub async fn submit_gps_live(
         return Err(ServerError::Conflict);
     }
 
-    use tlms::grpc::chemo_client::ChemoClient;
+    let submitted: Vec<(InsertGpsPoint, PointRef)> = batch
+        .iter()
+        .enumerate()
+        .map(|(index, gps_point)| {
+            let point_ref = PointRef {
+                index,
+                sequence: gps_point.sequence,
+            };
+            (gps_point.to_insert(path.0), point_ref)
+        })
+        .collect();
+
+    let (report, stored) = ingest_points(
+        path.0,
+        submitted,
+        &ingest_config.validation,
+        regions.geofence(trekkie_run.region),
+        &mut database_connection,
+    )?;
 
-    let grpc_host = match std::env::var("CHEMO_GRPC") {
-        Ok(value) => value,
-        Err(_e) => {
-            error!("NO grpc specified");
-            return Err(ServerError::InternalError);
+    // only newly stored points are forwarded, retried ones already reached chemo
+    chemo.enqueue(
+        stored
+            .into_iter()
+            .map(|(time, lat, lon)| grpc_point(&trekkie_run, time, lat, lon))
+            .collect(),
+    );
+
+    Ok(web::Json(report))
+}
+
+/// Writes a multipart field into a temporary file, so it can be parsed without holding it in
+/// memory. Fails as soon as the whole upload exceeds the configured size.
+pub(crate) async fn spool_field(
+    field: &mut Field,
+    received: &mut u64,
+    max_upload_size: u64,
+) -> Result<File, ServerError> {
+    let mut file = tempfile::tempfile().map_err(|e| {
+        error!("cannot create temporary file for upload {:?}", e);
+        ServerError::InternalError
+    })?;
+
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| {
+            warn!("upload stream broke off {}", e);
+            ServerError::InvalidData(format!("broken multipart stream: {}", e))
+        })?;
+
+        *received += chunk.len() as u64;
+        if *received > max_upload_size {
+            return Err(ServerError::PayloadTooLarge);
         }
-    };
 
-    match ChemoClient::connect(grpc_host.clone()).await {
-        Ok(mut client) => {
-            let grpc_gps = GrpcGpsPoint {
-                time: gps_point.timestamp.timestamp_millis() as u64,
-                id: 0,
-                region: trekkie_run.region,
-                lat: gps_point.lat,
-                lon: gps_point.lon,
-                line: trekkie_run.line,
-                run: trekkie_run.run,
-            };
+        file.write_all(&chunk).map_err(|e| {
+            error!("cannot write temporary file for upload {:?}", e);
+            ServerError::InternalError
+        })?;
+    }
+
+    file.rewind().map_err(|e| {
+        error!("cannot rewind temporary file for upload {:?}", e);
+        ServerError::InternalError
+    })?;
+
+    Ok(file)
+}
+
+/// name and content type of a multipart field
+pub(crate) fn uploaded_file(field: &Field) -> UploadedFile {
+    UploadedFile {
+        content_type: field
+            .content_type()
+            .map(|mime| mime.essence_str().to_string()),
+        file_name: field
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(|name| name.to_string()),
+    }
+}
+
+/// rejects uploads which announce a size above the limit before anything is read
+pub(crate) fn check_content_length(
+    req: &HttpRequest,
+    max_upload_size: u64,
+) -> Result<(), ServerError> {
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
 
-            let request = tonic::Request::new(grpc_gps);
-            if let Err(e) = client.receive_gps(request).await {
-                warn!("Error while sending gps point: {:?}", e);
+    if content_length.is_some_and(|length| length > max_upload_size) {
+        return Err(ServerError::PayloadTooLarge);
+    }
+
+    Ok(())
+}
+
+/// Reads the uploaded files and stores their points in a single transaction, so a malformed
+/// file or a failed insert leaves the run as it was before the upload.
+fn store_upload(
+    files: Vec<(UploadedFile, File)>,
+    run_id: Uuid,
+    format: Option<ImportFormat>,
+    forward: bool,
+    ingest_config: &IngestConfig,
+    geofence: Option<&Geofence>,
+    database_connection: &mut PgConnection,
+) -> Result<(IngestReport, Vec<StoredPoint>), ServerError> {
+    let mut failure: Option<ServerError> = None;
+
+    let result = database_connection.transaction::<_, diesel::result::Error, _>(|conn| {
+        // uploads are the way v1 runs, which are created finished, receive their points
+        let mut sink = ChunkedInsert::new(
+            run_id,
+            true,
+            conn,
+            &ingest_config.file_validation,
+            geofence,
+            forward,
+        );
+
+        // all files of the upload together may not decompress beyond the maximum upload size
+        let mut budget = SizeBudget::new(ingest_config.max_upload_size);
+        for (uploaded, file) in files {
+            if let Err(e) = import(file, &uploaded, format, &mut budget, run_id, &mut sink) {
+                error!("cannot read uploaded file for run {} {}", run_id, e);
+                failure = Some(if budget.exceeded() {
+                    ServerError::PayloadTooLarge
+                } else {
+                    ServerError::InvalidData(e)
+                });
+                return Err(diesel::result::Error::RollbackTransaction);
             }
         }
-        Err(e) => {
-            warn!(
-                "Cannot connect to GRPC Host: {} with error {:?}",
-                grpc_host, &e
-            );
-        }
-    };
 
-    use tlms::schema::gps_points::dsl::gps_points;
-
-    // taking all the points and inserting them into the database
-    match diesel::insert_into(gps_points)
-        .values(&InsertGpsPoint {
-            id: None,
-            trekkie_run: path.0,
-            timestamp: gps_point.timestamp.naive_utc(),
-            lat: gps_point.lat,
-            lon: gps_point.lon,
-            elevation: gps_point.elevation,
-            accuracy: gps_point.accuracy,
-            bearing: gps_point.bearing,
-            speed: gps_point.speed,
-            vertical_accuracy: gps_point.vertical_accuracy,
+        sink.finish().map_err(|e| {
+            failure = Some(e);
+            diesel::result::Error::RollbackTransaction
         })
-        .execute(&mut database_connection)
-    {
-        Ok(_) => Ok(HttpResponse::Ok().finish()),
-        Err(e) => {
-            error!("while trying to insert gps position run {:?}", e);
+    });
+
+    match (result, failure) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(e)) => Err(e),
+        (Err(e), None) => {
+            error!("cannot store upload for run {} {:?}", run_id, e);
             Err(ServerError::InternalError)
         }
     }
 }
 
-/// Takes the gpx file, saves it, and returns the travel id
+/// Takes track files in GPX, FIT, TCX, KML, GeoJSON, NMEA or CSV format, optionally gzip or zstd
+/// compressed, and stores their points for the run. The files are written to disk, parsed from
+/// there and inserted in chunks within one transaction, so either all points of the upload are
+/// stored or none.
 #[utoipa::path(
     post,
     path = "/v2/trekkie/{id}/gpx",
+    params(UploadQuery),
     responses(
-        (status = 200, description = "gpx file was successfully submitted"),
+        (status = 200, description = "track file was successfully submitted", body = IngestReport),
+        (status = 400, description = "file format is unknown, the file is malformed or the upload broke off"),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run was aborted or terminated without points"),
+        (status = 413, description = "upload or the decompressed files together exceed the maximum upload size"),
         (status = 500, description = "postgres pool error")
     ),
 )]
 #[post("/trekkie/{id}/gpx")]
 pub async fn travel_file_upload(
     pool: web::Data<DbPool>,
-    user: Identity,
+    chemo: web::Data<ChemoForwarder>,
+    ingest_config: web::Data<IngestConfig>,
+    regions: web::Data<Regions>,
+    user: Credentials,
     mut payload: Multipart,
     path: web::Path<(Uuid,)>,
-    _req: HttpRequest,
-) -> Result<HttpResponse, ServerError> {
+    query: web::Query<UploadQuery>,
+    req: HttpRequest,
+) -> Result<web::Json<IngestReport>, ServerError> {
+    check_content_length(&req, ingest_config.max_upload_size)?;
+
     // getting the database connection from pool
     let mut database_connection = match pool.get() {
         Ok(conn) => conn,
@@ -404,99 +1258,96 @@ pub async fn travel_file_upload(
     };
 
     let user_session = fetch_user(user, &mut database_connection)?;
-
-    use tlms::schema::trekkie_runs::dsl::trekkie_runs;
-    use tlms::schema::trekkie_runs::id as trekkie_id;
-
-    let trekkie_run = match trekkie_runs
-        .filter(trekkie_id.eq(path.0))
-        .first::<TrekkieRun>(&mut database_connection)
-    {
-        Ok(trekkie_run) => trekkie_run,
-        Err(e) => {
-            error!("database error while listing trekkie_runs {:?}", e);
-            return Err(ServerError::InternalError);
-        }
-    };
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
 
     if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
         return Err(ServerError::Forbidden);
     }
 
-    // collection of gps points
-    let mut point_list = Vec::new();
+    // v1 runs are created finished, only aborted and empty runs take no track anymore
+    let (state, _) = run_state(&trekkie_run, &mut database_connection)?;
+    if matches!(state, RunState::Aborted | RunState::Empty) {
+        return Err(ServerError::Conflict);
+    }
 
-    // iterate over multipart stream
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let _content_type = field.content_disposition();
-        let mut buffer: actix_web::web::BytesMut =
-            actix_web::web::BytesMut::with_capacity(1024 * 256);
-
-        // Merging all the multipart elements into one string
-        while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
-            buffer.extend(data);
-        }
+    // the whole upload is spooled to disk first, so it can be stored in a single transaction
+    let mut files: Vec<(UploadedFile, File)> = Vec::new();
+    let mut received: u64 = 0;
 
-        // Deserializing the string into a gpx object
-        match gpx::read(buffer.as_ref()) {
-            Ok(gpx) => {
-                // I feel like my IQ dropping around here, but dunno how to do it, especially given time
-                // situation in gpx crate
-                for track in gpx.tracks {
-                    for segment in track.segments {
-                        for point in segment.points {
-                            let soul = InsertGpsPoint {
-                                id: None,
-                                trekkie_run: path.0,
-                                lat: point.point().y(), // according to gpx crate team x and y are less
-                                lon: point.point().x(), // ambiguous for coordinates on a map
-                                elevation: point.elevation,
-                                timestamp: match point.time {
-                                    Some(time) => {
-                                        match chrono::naive::NaiveDateTime::parse_from_str(
-                                            &time.format().unwrap(),
-                                            "%Y-%m-%dT%H:%M:%S.%fZ",
-                                        ) {
-                                            Ok(result) => result,
-                                            Err(e) => {
-                                                error!("cannot parse timestamp {e}");
-                                                return Err(ServerError::BadClientData);
-                                            }
-                                        }
-                                    }
-                                    None => break,
-                                },
-
-                                accuracy: point.pdop,
-                                vertical_accuracy: point.vdop,
-                                bearing: None,
-                                speed: point.speed,
-                            };
-
-                            point_list.push(soul);
-                        }
-                    }
-                }
-            }
+    // iterate over multipart stream
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
             Err(e) => {
-                error!("cannot convert multipart string into gpx {:?}", e);
-                return Err(ServerError::BadClientData);
+                warn!("upload stream broke off {}", e);
+                return Err(ServerError::InvalidData(format!(
+                    "broken multipart stream: {}",
+                    e
+                )));
+            }
+        };
+
+        let uploaded = uploaded_file(&field);
+        let file = spool_field(&mut field, &mut received, ingest_config.max_upload_size).await?;
+        files.push((uploaded, file));
+    }
+
+    // uploading the same file again only stores the points which are not known yet
+    let forward = query.forward.unwrap_or(chemo.forward_uploads());
+    let (run_id, format, region) = (path.0, query.format, trekkie_run.region);
+    let upload_config = ingest_config.clone();
+    let upload_regions = regions.clone();
+    let finished = trekkie_run.finished;
+    let (report, stored) = web::block(move || {
+        let upload = store_upload(
+            files,
+            run_id,
+            format,
+            forward,
+            &upload_config,
+            upload_regions.geofence(region),
+            &mut database_connection,
+        );
+
+        // v1 runs are created finished and only get their points with the upload, so cleaned
+        // track, statistics and correlation have to be derived again
+        if let Ok((report, _)) = &upload {
+            if finished && report.inserted > 0 {
+                process_finished_run(
+                    run_id,
+                    upload_config.validation.max_speed,
+                    &mut database_connection,
+                );
             }
         }
+
+        upload
+    })
+    .await
+    .map_err(|e| {
+        error!("storing the upload of run {} panicked {:?}", run_id, e);
+        ServerError::InternalError
+    })??;
+
+    if !report.skipped.is_empty() {
+        warn!(
+            "skipped {} points of upload for run {}",
+            report.skipped.len(),
+            path.0
+        );
     }
 
-    use tlms::schema::gps_points::dsl::gps_points;
+    if forward {
+        let new_points: Vec<GrpcGpsPoint> = stored
+            .into_iter()
+            .map(|(time, lat, lon)| grpc_point(&trekkie_run, time, lat, lon))
+            .collect();
 
-    // taking all the points and inserting them into the database
-    match diesel::insert_into(gps_points)
-        .values(&point_list)
-        .execute(&mut database_connection)
-    {
-        Ok(_) => Ok(HttpResponse::Ok().finish()),
-        Err(e) => {
-            error!("while trying to insert trekkie run {:?}", e);
-            Err(ServerError::InternalError)
+        if !chemo.replay(new_points) {
+            warn!("cannot forward uploaded track, no chemo grpc host configured");
         }
     }
+
+    Ok(web::Json(report))
 }
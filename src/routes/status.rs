@@ -0,0 +1,31 @@
+use crate::chemo::{ChemoForwarder, ChemoStatus};
+use crate::routes::ServerError;
+
+use actix_web::{get, web, HttpRequest};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Health of the server and its forwarding to chemo
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ServerStatus {
+    pub chemo: ChemoStatus,
+}
+
+/// Returns the queue lengths of the chemo forwarder and the amount of points it dropped since the
+/// start. The counters contain no personal data, so no session is required.
+#[utoipa::path(
+    get,
+    path = "/v2/status",
+    responses(
+        (status = 200, description = "status of the server", body = ServerStatus),
+    ),
+)]
+#[get("/status")]
+pub async fn status(
+    chemo: web::Data<ChemoForwarder>,
+    _req: HttpRequest,
+) -> Result<web::Json<ServerStatus>, ServerError> {
+    Ok(web::Json(ServerStatus {
+        chemo: chemo.status(),
+    }))
+}
@@ -0,0 +1,230 @@
+use crate::models::ApiToken;
+use crate::routes::{
+    user::{fetch_user, random_secret, Credentials},
+    ServerError,
+};
+use crate::DbPool;
+
+use tlms::management::user::hash_password;
+
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Request body for creating an api token
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TokenCreation {
+    /// name which helps the user to tell the tokens apart
+    pub name: String,
+}
+
+/// Response body after creating an api token, the token is only returned once and has to be
+/// send as `Authorization: Bearer <token>` header
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CreatedToken {
+    pub id: Uuid,
+    pub name: String,
+    pub token: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Api token without its secret
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TokenInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+}
+
+impl From<ApiToken> for TokenInfo {
+    fn from(api_token: ApiToken) -> TokenInfo {
+        TokenInfo {
+            id: api_token.id,
+            name: api_token.name,
+            created_at: api_token.created_at,
+            last_used: api_token.last_used,
+        }
+    }
+}
+
+/// Creates a long lived api token for the authenticated user, which can be used instead of the
+/// session cookie.
+#[utoipa::path(
+    post,
+    path = "/v2/user/tokens",
+    request_body = TokenCreation,
+    responses(
+        (status = 200, description = "api token was created", body = CreatedToken),
+        (status = 401, description = "user is not authenticated"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[post("/user/tokens")]
+pub async fn token_create(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    body: web::Json<TokenCreation>,
+    _req: HttpRequest,
+) -> Result<web::Json<CreatedToken>, ServerError> {
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+
+    let secret = random_secret();
+    let token_hash = match hash_password(&secret) {
+        Some(data) => data,
+        None => {
+            error!("cannot hash api token");
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let api_token = ApiToken {
+        id: Uuid::new_v4(),
+        owner: user_session.user.id,
+        name: body.name.clone(),
+        token_hash,
+        created_at: Utc::now().naive_utc(),
+        last_used: None,
+        revoked: false,
+    };
+
+    use crate::schema::trekkie_api_tokens::dsl::trekkie_api_tokens;
+    if let Err(e) = diesel::insert_into(trekkie_api_tokens)
+        .values(&api_token)
+        .execute(&mut database_connection)
+    {
+        error!("while trying to insert api token {:?}", e);
+        return Err(ServerError::InternalError);
+    }
+    info!(
+        "user {} created api token {}",
+        user_session.user.id, api_token.id
+    );
+
+    Ok(web::Json(CreatedToken {
+        id: api_token.id,
+        name: api_token.name,
+        token: format!("{}.{}", api_token.id, secret),
+        created_at: api_token.created_at,
+    }))
+}
+
+/// Lists the api tokens of the authenticated user which are not revoked
+#[utoipa::path(
+    get,
+    path = "/v2/user/tokens",
+    responses(
+        (status = 200, description = "list of api tokens", body = Vec<TokenInfo>),
+        (status = 401, description = "user is not authenticated"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/user/tokens")]
+pub async fn token_list(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    _req: HttpRequest,
+) -> Result<web::Json<Vec<TokenInfo>>, ServerError> {
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+
+    use crate::schema::trekkie_api_tokens::dsl::trekkie_api_tokens;
+    use crate::schema::trekkie_api_tokens::{created_at, owner, revoked};
+
+    match trekkie_api_tokens
+        .filter(owner.eq(user_session.user.id))
+        .filter(revoked.eq(false))
+        .order(created_at.asc())
+        .load::<ApiToken>(&mut database_connection)
+    {
+        Ok(tokens) => Ok(web::Json(tokens.into_iter().map(TokenInfo::from).collect())),
+        Err(e) => {
+            error!("database error while listing api tokens {:?}", e);
+            Err(ServerError::InternalError)
+        }
+    }
+}
+
+/// Revokes an api token of the authenticated user, admins can revoke every token
+#[utoipa::path(
+    delete,
+    path = "/v2/user/tokens/{id}",
+    responses(
+        (status = 200, description = "api token was revoked"),
+        (status = 401, description = "user is not authenticated"),
+        (status = 403, description = "token belongs to another user"),
+        (status = 404, description = "token does not exist"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[delete("/user/tokens/{id}")]
+pub async fn token_revoke(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+
+    use crate::schema::trekkie_api_tokens::dsl::trekkie_api_tokens;
+    use crate::schema::trekkie_api_tokens::{id, revoked};
+
+    let api_token = match trekkie_api_tokens
+        .filter(id.eq(path.0))
+        .filter(revoked.eq(false))
+        .first::<ApiToken>(&mut database_connection)
+    {
+        Ok(found_token) => found_token,
+        Err(diesel::result::Error::NotFound) => return Err(ServerError::NotFound),
+        Err(e) => {
+            error!("database error while looking up api token {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    if !(user_session.is_admin() || user_session.user.id == api_token.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    match diesel::update(trekkie_api_tokens)
+        .filter(id.eq(path.0))
+        .set(revoked.eq(true))
+        .execute(&mut database_connection)
+    {
+        Ok(_) => {
+            info!("api token {} was revoked", path.0);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(e) => {
+            error!("cannot revoke api token {:?}", e);
+            Err(ServerError::InternalError)
+        }
+    }
+}
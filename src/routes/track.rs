@@ -0,0 +1,297 @@
+use crate::chemo::{grpc_point, ChemoForwarder};
+use crate::formats::TrackFormat;
+use crate::ingest::IngestConfig;
+use crate::models::CleanedPoint;
+use crate::processing::{clean_run, CleaningReport};
+use crate::routes::{
+    run::fetch_run,
+    user::{fetch_user, Credentials},
+    ServerError,
+};
+use crate::simplification::Simplification;
+use crate::statistics::update_statistics;
+use crate::DbPool;
+
+use tlms::locations::gps::GpsPoint;
+
+use actix_web::{get, http::header, post, web, HttpRequest, HttpResponse};
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+/// Query parameters for exporting a gps track
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TrackQuery {
+    /// output format, takes precedence over the accept header
+    pub format: Option<TrackFormat>,
+    /// export the cleaned track without outliers and with smoothed positions instead of the raw
+    /// gps points
+    pub cleaned: Option<bool>,
+    /// reduce the vertices of the track, douglas_peucker if only a tolerance is given
+    pub simplify: Option<Simplification>,
+    /// tolerance of the simplification in meters, defaults to 5
+    pub tolerance: Option<f64>,
+}
+
+/// tolerance of the track simplification in meters if none is given
+const DEFAULT_TOLERANCE: f64 = 5.0;
+
+/// Response of the replay endpoint
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReplayResponse {
+    /// amount of gps points which are send to chemo
+    pub queued: usize,
+}
+
+/// loads the raw gps points of the run in timestamp order
+fn load_gps_points(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<GpsPoint>, ServerError> {
+    use tlms::schema::gps_points::dsl::gps_points;
+    use tlms::schema::gps_points::{timestamp, trekkie_run as gps_trekkie_run};
+
+    gps_points
+        .filter(gps_trekkie_run.eq(run_id))
+        .order(timestamp.asc())
+        .load::<GpsPoint>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading gps points {:?}", e);
+            ServerError::InternalError
+        })
+}
+
+/// loads the cleaned track of the run in timestamp order as gps points
+fn load_cleaned_points(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<Vec<GpsPoint>, ServerError> {
+    use crate::schema::trekkie_cleaned_points::dsl::trekkie_cleaned_points;
+    use crate::schema::trekkie_cleaned_points::{timestamp, trekkie_run};
+
+    let cleaned = trekkie_cleaned_points
+        .filter(trekkie_run.eq(run_id))
+        .order(timestamp.asc())
+        .load::<CleanedPoint>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading cleaned track {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    Ok(cleaned
+        .into_iter()
+        .map(|point| GpsPoint {
+            id: point.raw_point,
+            trekkie_run: point.trekkie_run,
+            timestamp: point.timestamp,
+            lat: point.lat,
+            lon: point.lon,
+            elevation: point.elevation,
+            accuracy: None,
+            vertical_accuracy: None,
+            bearing: None,
+            speed: None,
+        })
+        .collect())
+}
+
+/// Exports the gps points of a trekkie run in timestamp order. The format is taken from the
+/// `format` query parameter or the accept header and defaults to gpx. With `cleaned=true` the
+/// cleaned track is exported, which only carries time, position and elevation. With `simplify`
+/// or `tolerance` the track is reduced to the vertices needed to draw it, the kept points are
+/// exported unchanged.
+#[utoipa::path(
+    get,
+    path = "/v2/trekkie/{id}/track",
+    params(TrackQuery),
+    responses(
+        (status = 200, description = "gps track of this run"),
+        (status = 400, description = "tolerance is negative or not a number"),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[get("/trekkie/{id}/track")]
+pub async fn export_track(
+    pool: web::Data<DbPool>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    query: web::Query<TrackQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    let format = query
+        .format
+        .or_else(|| {
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .and_then(TrackFormat::from_accept)
+        })
+        .unwrap_or(TrackFormat::Gpx);
+
+    let simplification = match (query.simplify, query.tolerance) {
+        (None, None) => None,
+        (method, tolerance) => {
+            let tolerance = tolerance.unwrap_or(DEFAULT_TOLERANCE);
+            if !(tolerance.is_finite() && tolerance >= 0.0) {
+                return Err(ServerError::InvalidData(format!(
+                    "invalid tolerance {}",
+                    tolerance
+                )));
+            }
+            Some((method.unwrap_or(Simplification::DouglasPeucker), tolerance))
+        }
+    };
+
+    let mut points = if query.cleaned.unwrap_or(false) {
+        load_cleaned_points(path.0, &mut database_connection)?
+    } else {
+        load_gps_points(path.0, &mut database_connection)?
+    };
+
+    if let Some((method, tolerance)) = simplification {
+        points = method.apply(points, tolerance);
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, format.content_type()))
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                trekkie_run.id,
+                format.extension()
+            ),
+        ))
+        .body(format.write(&trekkie_run, &points)))
+}
+
+/// Replays the recorded track of a finished run to chemo in timestamp order as a background job,
+/// so historic recordings can be fed into the live pipeline.
+#[utoipa::path(
+    post,
+    path = "/v2/trekkie/{id}/replay",
+    responses(
+        (status = 200, description = "track was queued for chemo", body = ReplayResponse),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 409, description = "run is not finished yet"),
+        (status = 500, description = "postgres pool error or chemo not configured")
+    ),
+)]
+#[post("/trekkie/{id}/replay")]
+pub async fn replay_track(
+    pool: web::Data<DbPool>,
+    chemo: web::Data<ChemoForwarder>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<ReplayResponse>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    // points of unfinished runs are still forwarded live
+    if !trekkie_run.finished {
+        return Err(ServerError::Conflict);
+    }
+
+    let points = load_gps_points(path.0, &mut database_connection)?;
+
+    let queued = points.len();
+    let grpc_points = points
+        .iter()
+        .map(|point| grpc_point(&trekkie_run, point.timestamp, point.lat, point.lon))
+        .collect();
+
+    if !chemo.replay(grpc_points) {
+        error!("cannot replay track, no chemo grpc host configured");
+        return Err(ServerError::InternalError);
+    }
+
+    Ok(web::Json(ReplayResponse { queued }))
+}
+
+/// Removes outliers from the gps points of a run and smooths the remaining positions. This
+/// happens automatically when a run is finished, the endpoint repeats it e.g. after more points
+/// were uploaded. The cleaned track replaces the previous one, the raw points are kept, and the
+/// run statistics are computed again.
+#[utoipa::path(
+    post,
+    path = "/v2/trekkie/{id}/clean",
+    responses(
+        (status = 200, description = "cleaned track was stored", body = CleaningReport),
+        (status = 403, description = "user is not the owner of this run"),
+        (status = 404, description = "run does not exist"),
+        (status = 500, description = "postgres pool error")
+    ),
+)]
+#[post("/trekkie/{id}/clean")]
+pub async fn clean_track(
+    pool: web::Data<DbPool>,
+    ingest_config: web::Data<IngestConfig>,
+    user: Credentials,
+    path: web::Path<(Uuid,)>,
+    _req: HttpRequest,
+) -> Result<web::Json<CleaningReport>, ServerError> {
+    // getting the database connection from pool
+    let mut database_connection = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("cannot get connection from connection pool {:?}", e);
+            return Err(ServerError::InternalError);
+        }
+    };
+
+    let user_session = fetch_user(user, &mut database_connection)?;
+    let trekkie_run = fetch_run(path.0, &mut database_connection)?;
+
+    if !(user_session.is_admin() || user_session.user.id == trekkie_run.owner) {
+        return Err(ServerError::Forbidden);
+    }
+
+    let report = clean_run(
+        path.0,
+        ingest_config.validation.max_speed,
+        &mut database_connection,
+    )?;
+
+    // unfinished runs still change, their statistics are only computed on request
+    if trekkie_run.finished {
+        update_statistics(path.0, &mut database_connection)?;
+    }
+
+    Ok(web::Json(report))
+}
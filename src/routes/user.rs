@@ -1,3 +1,4 @@
+use crate::models::ApiToken;
 use crate::routes::{Response, ServerError};
 use crate::DbPool;
 
@@ -7,8 +8,10 @@ use log::{error, info};
 use uuid::Uuid;
 
 use actix_identity::Identity;
-use actix_web::{post, web, HttpMessage, HttpRequest};
+use actix_web::{dev::Payload, http::header, post, web, FromRequest, HttpMessage, HttpRequest};
+use chrono::Utc;
 use diesel::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use futures::future::LocalBoxFuture;
 use rand::{distr::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
@@ -27,27 +30,119 @@ pub struct UserLogin {
     pub password: String,
 }
 
-/// takes a cookie and returnes the corresponging user struct
+/// Credentials of a request, either the session cookie or an api token from the
+/// `Authorization: Bearer` header
+pub enum Credentials {
+    Session(Identity),
+    Token(String),
+}
+
+impl FromRequest for Credentials {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+
+        if let Some(token) = bearer {
+            return Box::pin(async move { Ok(Credentials::Token(token)) });
+        }
+
+        let identity = Identity::from_request(req, payload);
+        Box::pin(async move {
+            identity
+                .await
+                .map(Credentials::Session)
+                .map_err(|_| ServerError::Unauthorized.into())
+        })
+    }
+}
+
+/// takes a cookie or api token and returnes the corresponging user struct
 pub fn fetch_user(
-    user: Identity,
+    user: Credentials,
     database_connection: &mut PgConnection,
 ) -> Result<AuthorizedUser, ServerError> {
+    let user_id = match user {
+        Credentials::Session(identity) => session_user_id(identity)?,
+        Credentials::Token(token) => token_user_id(&token, database_connection)?,
+    };
+
+    AuthorizedUser::from_postgres(&user_id, database_connection).ok_or(ServerError::Unauthorized)
+}
+
+/// user uuid from the session cookie
+fn session_user_id(user: Identity) -> Result<Uuid, ServerError> {
     // user uuid from currently authenticat
-    let user_id: Uuid = match user.id() {
+    match user.id() {
         Ok(found_id) => match Uuid::parse_str(&found_id) {
-            Ok(parsed_uuid) => parsed_uuid,
+            Ok(parsed_uuid) => Ok(parsed_uuid),
             Err(e) => {
                 error!("problem with decoding id from cookie {:?}", e);
-                return Err(ServerError::BadClientData);
+                Err(ServerError::Unauthorized)
             }
         },
         Err(e) => {
             error!("problem with fetching id from cookie {:?}", e);
-            return Err(ServerError::BadClientData);
+            Err(ServerError::Unauthorized)
+        }
+    }
+}
+
+/// Verifies an api token of the form `<token id>.<secret>` and returns the uuid of its owner
+fn token_user_id(token: &str, database_connection: &mut PgConnection) -> Result<Uuid, ServerError> {
+    let (token_id, secret) = match token.split_once('.') {
+        Some((token_id, secret)) => match Uuid::parse_str(token_id) {
+            Ok(parsed_uuid) => (parsed_uuid, secret),
+            Err(_) => return Err(ServerError::Unauthorized),
+        },
+        None => return Err(ServerError::Unauthorized),
+    };
+
+    use crate::schema::trekkie_api_tokens::dsl::trekkie_api_tokens;
+    use crate::schema::trekkie_api_tokens::{id, last_used, revoked};
+
+    let api_token = match trekkie_api_tokens
+        .filter(id.eq(token_id))
+        .filter(revoked.eq(false))
+        .first::<ApiToken>(database_connection)
+    {
+        Ok(found_token) => found_token,
+        Err(diesel::result::Error::NotFound) => return Err(ServerError::Unauthorized),
+        Err(e) => {
+            error!("database error while looking up api token {:?}", e);
+            return Err(ServerError::InternalError);
         }
     };
 
-    AuthorizedUser::from_postgres(&user_id, database_connection).ok_or(ServerError::BadClientData)
+    if !verify_password(secret, &api_token.token_hash) {
+        error!("invalid secret for api token {}", token_id);
+        return Err(ServerError::Unauthorized);
+    }
+
+    if let Err(e) = diesel::update(trekkie_api_tokens)
+        .filter(id.eq(token_id))
+        .set(last_used.eq(Utc::now().naive_utc()))
+        .execute(database_connection)
+    {
+        error!("cannot update last usage of api token {:?}", e);
+    }
+
+    Ok(api_token.owner)
+}
+
+/// random alphanumeric string used for passwords and token secrets
+pub fn random_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
 }
 
 /// Request to this endpoint creates minimal and unpriviledged trekkie user. If the call was succesful
@@ -73,11 +168,7 @@ pub async fn user_create(
         }
     };
     let user_id = Uuid::new_v4();
-    let password: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
+    let password = random_secret();
 
     let hashed_password = match hash_password(&password) {
         Some(data) => data,
@@ -0,0 +1,96 @@
+// Tables which are owned by trekkie, everything else lives in tlms::schema. The migrations for
+// these tables are in the migrations folder and are applied on startup.
+
+diesel::table! {
+    trekkie_api_tokens (id) {
+        id -> Uuid,
+        owner -> Uuid,
+        name -> Text,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+        revoked -> Bool,
+    }
+}
+
+diesel::table! {
+    trekkie_v1_normalized_runs (trekkie_run) {
+        trekkie_run -> Uuid,
+        shifted_by_minutes -> Int4,
+        normalized_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    trekkie_cleaned_points (trekkie_run, timestamp) {
+        trekkie_run -> Uuid,
+        timestamp -> Timestamp,
+        lat -> Float8,
+        lon -> Float8,
+        elevation -> Nullable<Float8>,
+        raw_point -> Int8,
+    }
+}
+
+diesel::table! {
+    trekkie_run_stats (trekkie_run) {
+        trekkie_run -> Uuid,
+        points -> Int8,
+        distance -> Float8,
+        duration -> Float8,
+        moving_time -> Float8,
+        average_speed -> Nullable<Float8>,
+        max_speed -> Nullable<Float8>,
+        longest_gap -> Nullable<Float8>,
+        mean_accuracy -> Nullable<Float8>,
+        intervals_1s -> Int8,
+        intervals_2s -> Int8,
+        intervals_5s -> Int8,
+        intervals_10s -> Int8,
+        intervals_30s -> Int8,
+        intervals_longer -> Int8,
+        computed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    trekkie_correlations (trekkie_run, telegram) {
+        trekkie_run -> Uuid,
+        telegram -> Int8,
+        time -> Timestamp,
+        region -> Int8,
+        reporting_point -> Int4,
+        junction -> Int4,
+        request_status -> Int2,
+        lat -> Float8,
+        lon -> Float8,
+        fix_delta -> Float8,
+        confidence -> Float8,
+        created_at -> Timestamp,
+        accuracy -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    trekkie_reporting_points (region, reporting_point) {
+        region -> Int8,
+        reporting_point -> Int4,
+        lat -> Float8,
+        lon -> Float8,
+        samples -> Int4,
+        outliers -> Int4,
+        runs -> Int4,
+        spread -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    trekkie_run_activity (trekkie_run) {
+        trekkie_run -> Uuid,
+        created_at -> Timestamp,
+        last_point_at -> Nullable<Timestamp>,
+        state -> Text,
+        aborted_reason -> Nullable<Text>,
+    }
+}
@@ -0,0 +1,180 @@
+use crate::structs::Args;
+
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, CookieJar, Key, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, COOKIE},
+    middleware::Next,
+    web, Error,
+};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+
+use std::fs;
+
+/// name of the cookie the session middleware stores the session in
+const SESSION_COOKIE: &str = "id";
+
+/// Keys the session cookies are encrypted with. Cookies encrypted with the previous key are
+/// accepted until the grace period is over and are re-encrypted with the current key.
+pub struct SessionKeys {
+    pub current: Key,
+    previous: Option<Key>,
+    previous_valid_until: DateTime<Utc>,
+}
+
+/// reads a session key file, the file has to contain at least 64 bytes
+fn read_key(path: &str) -> Key {
+    let content = fs::read(path).expect("cannot read session key file!");
+    Key::try_from(content.as_slice()).expect("session key file needs at least 64 bytes!")
+}
+
+impl SessionKeys {
+    /// Loads the session keys from the configured files. The grace period of the previous key
+    /// starts when the current key file was last modified, so restarts do not extend it. Without a
+    /// configured key file a random key is generated and sessions do not survive restarts.
+    pub fn load(args: &Args) -> SessionKeys {
+        let current_path = match &args.session_key_path {
+            Some(path) => path,
+            None => {
+                warn!("no session key file configured, sessions are lost on restart");
+                return SessionKeys {
+                    current: Key::generate(),
+                    previous: None,
+                    previous_valid_until: Utc::now(),
+                };
+            }
+        };
+
+        let current = read_key(current_path);
+
+        let rotated_at: DateTime<Utc> = match fs::metadata(current_path).and_then(|m| m.modified())
+        {
+            Ok(modified) => modified.into(),
+            Err(e) => {
+                warn!("cannot read modification time of session key file {:?}", e);
+                Utc::now()
+            }
+        };
+        let previous_valid_until =
+            rotated_at + Duration::hours(args.session_key_grace_period as i64);
+
+        let previous = match &args.previous_session_key_path {
+            Some(path) if Utc::now() < previous_valid_until => {
+                info!(
+                    "accepting sessions of the previous key until {}",
+                    previous_valid_until
+                );
+                Some(read_key(path))
+            }
+            Some(_) => {
+                warn!("grace period of the previous session key is over, ignoring it");
+                None
+            }
+            None => None,
+        };
+
+        SessionKeys {
+            current,
+            previous,
+            previous_valid_until,
+        }
+    }
+
+    /// Returns the session cookie re-encrypted with the current key if the request carries a
+    /// session cookie that can only be decrypted with the previous key.
+    fn migrate(&self, cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+        let previous = self.previous.as_ref()?;
+
+        if Utc::now() >= self.previous_valid_until {
+            return None;
+        }
+
+        let jar = CookieJar::new();
+        if jar.private(&self.current).decrypt(cookie.clone()).is_some() {
+            return None;
+        }
+
+        let plain = jar.private(previous).decrypt(cookie.clone())?;
+
+        let mut migrated_jar = CookieJar::new();
+        migrated_jar.private_mut(&self.current).add(plain);
+
+        migrated_jar.get(SESSION_COOKIE).cloned()
+    }
+}
+
+/// Middleware which runs before the session middleware and moves sessions that were created with
+/// the previous key over to the current key. The request cookie is replaced so the session
+/// middleware accepts it and the client receives the re-encrypted cookie.
+pub async fn migrate_session_cookie(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let keys = req.app_data::<web::Data<SessionKeys>>().cloned();
+
+    let mut migrated_cookie = None;
+
+    if let Some(keys) = keys {
+        let mut cookies: Vec<Cookie<'static>> = req
+            .headers()
+            .get_all(COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|value| Cookie::parse_encoded(value.trim().to_string()).ok())
+            .collect();
+
+        for cookie in cookies.iter_mut() {
+            if cookie.name() != SESSION_COOKIE {
+                continue;
+            }
+
+            if let Some(migrated) = keys.migrate(cookie) {
+                *cookie = migrated.clone();
+                migrated_cookie = Some(migrated);
+            }
+        }
+
+        if migrated_cookie.is_some() {
+            let header = cookies
+                .iter()
+                .map(|cookie| cookie.encoded().to_string())
+                .collect::<Vec<String>>()
+                .join("; ");
+
+            match HeaderValue::from_str(&header) {
+                Ok(value) => {
+                    req.headers_mut().insert(COOKIE, value);
+                }
+                Err(e) => {
+                    error!("cannot build migrated cookie header {:?}", e);
+                    migrated_cookie = None;
+                }
+            }
+        }
+    }
+
+    let mut res = next.call(req).await?;
+
+    if let Some(mut cookie) = migrated_cookie {
+        // the session middleware sets its own cookie if the session changed
+        let already_set = res
+            .response()
+            .cookies()
+            .any(|set| set.name() == SESSION_COOKIE);
+
+        if !already_set {
+            cookie.set_path("/");
+            cookie.set_secure(true);
+            cookie.set_http_only(true);
+            cookie.set_same_site(SameSite::Lax);
+
+            if let Err(e) = res.response_mut().add_cookie(&cookie) {
+                error!("cannot set migrated session cookie {:?}", e);
+            }
+        }
+    }
+
+    Ok(res)
+}
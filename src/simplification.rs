@@ -0,0 +1,275 @@
+use tlms::locations::gps::GpsPoint;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// meters per degree of latitude
+const METERS_PER_DEGREE: f64 = 111_195.0;
+
+/// Algorithm used to reduce the vertices of a track
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Simplification {
+    /// keeps every vertex which deviates more than the tolerance in meters from the simplified
+    /// line
+    DouglasPeucker,
+    /// removes vertices whose triangle with their neighbours covers less than the squared
+    /// tolerance in square meters
+    Visvalingam,
+}
+
+impl Simplification {
+    /// Returns the kept points in their original order, first and last point are always kept.
+    /// The kept points are not modified, so their timestamps stay exact.
+    pub fn apply(&self, points: Vec<GpsPoint>, tolerance: f64) -> Vec<GpsPoint> {
+        if points.len() < 3 {
+            return points;
+        }
+
+        let projected = project(&points);
+        let keep = match self {
+            Simplification::DouglasPeucker => douglas_peucker(&projected, tolerance),
+            Simplification::Visvalingam => visvalingam(&projected, tolerance * tolerance),
+        };
+
+        points
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .map(|(point, _)| point)
+            .collect()
+    }
+}
+
+/// projects the positions onto a plane in meters around the first point
+fn project(points: &[GpsPoint]) -> Vec<(f64, f64)> {
+    let origin = &points[0];
+    let lon_scale = METERS_PER_DEGREE * origin.lat.to_radians().cos();
+
+    points
+        .iter()
+        .map(|point| {
+            (
+                (point.lon - origin.lon) * lon_scale,
+                (point.lat - origin.lat) * METERS_PER_DEGREE,
+            )
+        })
+        .collect()
+}
+
+/// distance of the point from the segment between start and end
+fn segment_distance(point: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let length = dx * dx + dy * dy;
+    if length == 0.0 {
+        return (point.0 - start.0).hypot(point.1 - start.1);
+    }
+
+    let t = (((point.0 - start.0) * dx + (point.1 - start.1) * dy) / length).clamp(0.0, 1.0);
+    (point.0 - start.0 - t * dx).hypot(point.1 - start.1 - t * dy)
+}
+
+fn douglas_peucker(points: &[(f64, f64)], tolerance: f64) -> Vec<bool> {
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // ranges which still have to be split, a stack instead of recursion for long tracks
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let farthest = (start + 1..end)
+            .map(|index| {
+                (
+                    index,
+                    segment_distance(points[index], points[start], points[end]),
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((index, distance)) = farthest {
+            if distance > tolerance {
+                keep[index] = true;
+                ranges.push((start, index));
+                ranges.push((index, end));
+            }
+        }
+    }
+
+    keep
+}
+
+/// area of the triangle spanned by three points
+fn triangle_area(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+    ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs() / 2.0
+}
+
+/// vertex in the removal queue, ordered so the smallest area is popped first
+struct Candidate {
+    area: f64,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .area
+            .total_cmp(&self.area)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+fn visvalingam(points: &[(f64, f64)], min_area: f64) -> Vec<bool> {
+    let count = points.len();
+    let mut keep = vec![true; count];
+    let mut previous: Vec<usize> = (0..count).map(|index| index.saturating_sub(1)).collect();
+    let mut next: Vec<usize> = (0..count).map(|index| index + 1).collect();
+    // current area of every vertex, entries in the queue with another area are outdated
+    let mut areas = vec![f64::INFINITY; count];
+
+    let mut queue = BinaryHeap::new();
+    for index in 1..count - 1 {
+        areas[index] = triangle_area(points[index - 1], points[index], points[index + 1]);
+        queue.push(Candidate {
+            area: areas[index],
+            index,
+        });
+    }
+
+    while let Some(Candidate { area, index }) = queue.pop() {
+        if !keep[index] || area != areas[index] {
+            continue;
+        }
+        if area >= min_area {
+            break;
+        }
+
+        keep[index] = false;
+        let (before, after) = (previous[index], next[index]);
+        next[before] = after;
+        previous[after] = before;
+
+        // the neighbours get a new triangle, their area never drops below the removed one so
+        // the removal order stays monotonic
+        for neighbour in [before, after] {
+            if neighbour == 0 || neighbour == count - 1 {
+                continue;
+            }
+            let area = triangle_area(
+                points[previous[neighbour]],
+                points[neighbour],
+                points[next[neighbour]],
+            )
+            .max(area);
+            areas[neighbour] = area;
+            queue.push(Candidate {
+                area,
+                index: neighbour,
+            });
+        }
+    }
+
+    keep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::tests::gps_point;
+
+    const ALGORITHMS: [Simplification; 2] =
+        [Simplification::DouglasPeucker, Simplification::Visvalingam];
+
+    fn ids(points: &[GpsPoint]) -> Vec<i64> {
+        points.iter().map(|point| point.id).collect()
+    }
+
+    /// track east and then north around a corner with a small wiggle on the first leg
+    fn corner() -> Vec<GpsPoint> {
+        vec![
+            gps_point(0, 0.0, 0.0),
+            gps_point(1, 1.0, 100.0),
+            gps_point(2, 0.0, 200.0),
+            gps_point(3, 100.0, 200.0),
+            gps_point(4, 200.0, 200.0),
+        ]
+    }
+
+    #[test]
+    fn few_points() {
+        for algorithm in ALGORITHMS {
+            assert!(algorithm.apply(Vec::new(), 10.0).is_empty());
+            assert_eq!(ids(&algorithm.apply(corner()[..1].to_vec(), 10.0)), [0]);
+            assert_eq!(ids(&algorithm.apply(corner()[..2].to_vec(), 10.0)), [0, 1]);
+        }
+    }
+
+    #[test]
+    fn keeps_corner() {
+        for algorithm in ALGORITHMS {
+            assert_eq!(ids(&algorithm.apply(corner(), 20.0)), [0, 2, 4]);
+        }
+    }
+
+    #[test]
+    fn small_tolerance_keeps_wiggle() {
+        // the wiggle deviates 1 m from the line and spans a triangle of 100 m²
+        assert_eq!(
+            ids(&Simplification::DouglasPeucker.apply(corner(), 0.5)),
+            [0, 1, 2, 4]
+        );
+        assert_eq!(
+            ids(&Simplification::Visvalingam.apply(corner(), 5.0)),
+            [0, 1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn degenerate_tracks() {
+        // a vehicle standing still, all points share their position
+        let standing: Vec<GpsPoint> = (0..5).map(|second| gps_point(second, 0.0, 0.0)).collect();
+        // driving to a point and back on the same line
+        let reversing = vec![
+            gps_point(0, 0.0, 0.0),
+            gps_point(1, 0.0, 100.0),
+            gps_point(2, 0.0, 50.0),
+            gps_point(3, 0.0, 0.0),
+        ];
+
+        for algorithm in ALGORITHMS {
+            assert_eq!(ids(&algorithm.apply(standing.clone(), 1.0)), [0, 4]);
+        }
+        // the turning point is kept although first and last point coincide
+        assert_eq!(
+            ids(&Simplification::DouglasPeucker.apply(reversing, 1.0)),
+            [0, 1, 3]
+        );
+    }
+
+    #[test]
+    fn segment_distances() {
+        assert_eq!(segment_distance((5.0, 3.0), (0.0, 0.0), (10.0, 0.0)), 3.0);
+        // beyond the end of the segment the distance to the end point counts
+        assert_eq!(segment_distance((13.0, 4.0), (0.0, 0.0), (10.0, 0.0)), 5.0);
+        // zero length segment
+        assert_eq!(segment_distance((3.0, 4.0), (0.0, 0.0), (0.0, 0.0)), 5.0);
+        assert_eq!(triangle_area((0.0, 0.0), (4.0, 0.0), (0.0, 3.0)), 6.0);
+        assert_eq!(triangle_area((0.0, 0.0), (1.0, 1.0), (2.0, 2.0)), 0.0);
+    }
+}
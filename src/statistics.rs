@@ -0,0 +1,230 @@
+use crate::models::RunStats;
+use crate::processing::{distance, load_track};
+use crate::routes::ServerError;
+
+use tlms::locations::gps::GpsPoint;
+use tlms::trekkie::TrekkieRun;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::{ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// slowest speed in meters per second which still counts as moving
+const MOVING_SPEED: f64 = 0.5;
+
+/// upper bounds in seconds of the sampling interval buckets, longer intervals fall into a last
+/// open bucket
+const INTERVAL_BOUNDS: [f64; 5] = [1.0, 2.0, 5.0, 10.0, 30.0];
+
+/// Amount of sampling intervals up to the given length
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct IntervalBucket {
+    /// upper bound in seconds, missing for the bucket of all longer intervals
+    pub up_to_seconds: Option<f64>,
+    pub count: i64,
+}
+
+/// Quality and movement figures of a run
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct RunStatistics {
+    /// amount of stored gps points
+    pub points: i64,
+    /// length of the cleaned track in meters
+    pub distance: f64,
+    /// seconds between the first and the last point
+    pub duration: f64,
+    /// seconds spent moving faster than 0.5 m/s
+    pub moving_time: f64,
+    /// distance divided by moving time in meters per second
+    pub average_speed: Option<f64>,
+    /// fastest movement between two points of the cleaned track in meters per second
+    pub max_speed: Option<f64>,
+    /// longest time without a point in seconds
+    pub longest_gap: Option<f64>,
+    /// mean horizontal accuracy of the points which report one, in meters
+    pub mean_accuracy: Option<f64>,
+    /// distribution of the time between two consecutive points
+    pub intervals: Vec<IntervalBucket>,
+    pub computed_at: NaiveDateTime,
+}
+
+impl From<RunStats> for RunStatistics {
+    fn from(stats: RunStats) -> Self {
+        let counts = [
+            stats.intervals_1s,
+            stats.intervals_2s,
+            stats.intervals_5s,
+            stats.intervals_10s,
+            stats.intervals_30s,
+            stats.intervals_longer,
+        ];
+        let bounds = INTERVAL_BOUNDS.iter().copied().map(Some).chain([None]);
+
+        RunStatistics {
+            points: stats.points,
+            distance: stats.distance,
+            duration: stats.duration,
+            moving_time: stats.moving_time,
+            average_speed: stats.average_speed,
+            max_speed: stats.max_speed,
+            longest_gap: stats.longest_gap,
+            mean_accuracy: stats.mean_accuracy,
+            intervals: bounds
+                .zip(counts)
+                .map(|(up_to_seconds, count)| IntervalBucket {
+                    up_to_seconds,
+                    count,
+                })
+                .collect(),
+            computed_at: stats.computed_at,
+        }
+    }
+}
+
+/// seconds between two timestamps
+fn seconds_between(from: NaiveDateTime, to: NaiveDateTime) -> f64 {
+    (to - from).num_milliseconds() as f64 / 1000.0
+}
+
+/// Computes the statistics of a run. Sampling, gaps and accuracy describe the raw points, while
+/// distance and speeds are taken from the track positions, which are the cleaned ones if
+/// available. Both lists have to be ordered by time.
+fn compute(run_id: Uuid, raw_points: &[GpsPoint], track: &[(NaiveDateTime, f64, f64)]) -> RunStats {
+    let mut intervals = [0i64; INTERVAL_BOUNDS.len() + 1];
+    let mut longest_gap: Option<f64> = None;
+    for pair in raw_points.windows(2) {
+        let seconds = seconds_between(pair[0].timestamp, pair[1].timestamp);
+        let bucket = INTERVAL_BOUNDS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(INTERVAL_BOUNDS.len());
+        intervals[bucket] += 1;
+        longest_gap = Some(longest_gap.map_or(seconds, |gap| gap.max(seconds)));
+    }
+
+    let accuracies: Vec<f64> = raw_points
+        .iter()
+        .filter_map(|point| point.accuracy)
+        .filter(|accuracy| accuracy.is_finite())
+        .collect();
+    let mean_accuracy =
+        (!accuracies.is_empty()).then(|| accuracies.iter().sum::<f64>() / accuracies.len() as f64);
+
+    let mut total_distance = 0.0;
+    let mut moving_time = 0.0;
+    let mut max_speed: Option<f64> = None;
+    for pair in track.windows(2) {
+        let (from_time, from_lat, from_lon) = pair[0];
+        let (to_time, to_lat, to_lon) = pair[1];
+        let meters = distance(from_lat, from_lon, to_lat, to_lon);
+        let seconds = seconds_between(from_time, to_time);
+        total_distance += meters;
+
+        if seconds > 0.0 {
+            let speed = meters / seconds;
+            if speed >= MOVING_SPEED {
+                moving_time += seconds;
+            }
+            max_speed = Some(max_speed.map_or(speed, |max| max.max(speed)));
+        }
+    }
+
+    let duration = match (raw_points.first(), raw_points.last()) {
+        (Some(first), Some(last)) => seconds_between(first.timestamp, last.timestamp),
+        _ => 0.0,
+    };
+
+    RunStats {
+        trekkie_run: run_id,
+        points: raw_points.len() as i64,
+        distance: total_distance,
+        duration,
+        moving_time,
+        average_speed: (moving_time > 0.0).then(|| total_distance / moving_time),
+        max_speed,
+        longest_gap,
+        mean_accuracy,
+        intervals_1s: intervals[0],
+        intervals_2s: intervals[1],
+        intervals_5s: intervals[2],
+        intervals_10s: intervals[3],
+        intervals_30s: intervals[4],
+        intervals_longer: intervals[5],
+        computed_at: Utc::now().naive_utc(),
+    }
+}
+
+/// computes the statistics of the run from its stored points without storing them
+fn compute_statistics(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<RunStats, ServerError> {
+    use tlms::schema::gps_points::dsl as gps;
+
+    let raw_points = gps::gps_points
+        .filter(gps::trekkie_run.eq(run_id))
+        .order(gps::timestamp.asc())
+        .load::<GpsPoint>(database_connection)
+        .map_err(|e| {
+            error!("database error while loading gps points {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    let track = load_track(run_id, database_connection)?;
+
+    Ok(compute(run_id, &raw_points, &track))
+}
+
+/// computes the statistics of the run from its stored points and replaces the stored ones
+pub fn update_statistics(
+    run_id: Uuid,
+    database_connection: &mut PgConnection,
+) -> Result<RunStats, ServerError> {
+    use crate::schema::trekkie_run_stats::dsl::{trekkie_run, trekkie_run_stats};
+
+    let stats = compute_statistics(run_id, database_connection)?;
+
+    diesel::insert_into(trekkie_run_stats)
+        .values(&stats)
+        .on_conflict(trekkie_run)
+        .do_update()
+        .set(&stats)
+        .execute(database_connection)
+        .map_err(|e| {
+            error!("while trying to store run statistics {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    Ok(stats)
+}
+
+/// Stored statistics of a finished run, computed and stored first if there are none yet. The
+/// statistics of unfinished runs change with every point, so they are computed on every request
+/// and never stored.
+pub fn fetch_statistics(
+    trekkie_run: &TrekkieRun,
+    database_connection: &mut PgConnection,
+) -> Result<RunStats, ServerError> {
+    use crate::schema::trekkie_run_stats::dsl::{trekkie_run as stats_run, trekkie_run_stats};
+
+    if !trekkie_run.finished {
+        return compute_statistics(trekkie_run.id, database_connection);
+    }
+
+    let stored = trekkie_run_stats
+        .filter(stats_run.eq(trekkie_run.id))
+        .first::<RunStats>(database_connection)
+        .optional()
+        .map_err(|e| {
+            error!("database error while loading run statistics {:?}", e);
+            ServerError::InternalError
+        })?;
+
+    match stored {
+        Some(stats) => Ok(stats),
+        None => update_statistics(trekkie_run.id, database_connection),
+    }
+}
@@ -1,6 +1,8 @@
 extern crate clap;
 //extern crate derive_builder;
 
+use crate::validation::ValidationMode;
+
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 
@@ -23,6 +25,93 @@ pub struct Args {
 
     #[arg(short, long, action)]
     pub swagger: bool,
+
+    /// address of chemo, live gps points are not forwarded if it is unset
+    #[arg(long, env = "CHEMO_GRPC")]
+    pub chemo_grpc: Option<String>,
+
+    /// amount of live and of replayed gps points which are buffered while chemo is unreachable
+    #[arg(long, default_value_t = 10000)]
+    pub chemo_queue_size: usize,
+
+    /// file with at least 64 bytes the session cookies are encrypted with, a random key is used
+    /// if unset
+    #[arg(long, env = "TREKKIE_SESSION_KEY_PATH")]
+    pub session_key_path: Option<String>,
+
+    /// key file which was used before the last rotation, still accepted during the grace period
+    #[arg(long, env = "TREKKIE_PREVIOUS_SESSION_KEY_PATH")]
+    pub previous_session_key_path: Option<String>,
+
+    /// hours after the last change of the session key file in which the previous key is accepted
+    #[arg(long, default_value_t = 168)]
+    pub session_key_grace_period: u32,
+
+    /// json file with region specific settings like the timezone
+    #[arg(long, env = "TREKKIE_REGION_CONFIG")]
+    pub region_config: Option<String>,
+
+    /// timezone used for regions without configured timezone
+    #[arg(long, default_value_t = String::from("Europe/Berlin"))]
+    pub default_timezone: String,
+
+    /// radius in kilometers of the geofence around the region center from the tlms database,
+    /// used for regions without geofence in the region config
+    #[arg(long, default_value_t = 30.0)]
+    pub region_radius: f64,
+
+    /// converts the times of v1 runs submitted before the timezone fix into utc and exits
+    #[arg(long, action)]
+    pub renormalize_v1_runs: bool,
+
+    /// do not apply the trekkie migrations on startup, for databases whose schema is managed
+    /// elsewhere and users without rights to create tables
+    #[arg(long, action, env = "TREKKIE_SKIP_MIGRATIONS")]
+    pub skip_migrations: bool,
+
+    /// replay uploaded gpx tracks to chemo unless the upload request says otherwise
+    #[arg(long, action)]
+    pub forward_uploads: bool,
+
+    /// maximum size of all files of one track upload or import in bytes, before and after
+    /// decompression
+    #[arg(long, env = "TREKKIE_MAX_UPLOAD_SIZE", default_value_t = 64 * 1024 * 1024)]
+    pub max_upload_size: u64,
+
+    /// whether gps points which fail the validation are rejected or stored and flagged
+    #[arg(long, value_enum, env = "TREKKIE_INVALID_POINTS", default_value_t = ValidationMode::Reject)]
+    pub invalid_points: ValidationMode,
+
+    /// how many seconds a point may lie in the future to tolerate clock drift of the phone
+    #[arg(long, env = "TREKKIE_MAX_FUTURE_SECONDS", default_value_t = 300)]
+    pub max_future_seconds: i64,
+
+    /// how many days a live, batch or websocket point may lie in the past, track files have no
+    /// age limit
+    #[arg(long, env = "TREKKIE_MAX_POINT_AGE_DAYS", default_value_t = 365)]
+    pub max_point_age_days: i64,
+
+    /// largest accepted horizontal accuracy of a point in meters
+    #[arg(long, env = "TREKKIE_MAX_ACCURACY", default_value_t = 1000.0)]
+    pub max_accuracy: f64,
+
+    /// largest accepted speed of a point in meters per second
+    #[arg(long, env = "TREKKIE_MAX_SPEED", default_value_t = 70.0)]
+    pub max_speed: f64,
+
+    /// whether gps points outside of the geofence of their region are rejected or stored and
+    /// flagged
+    #[arg(long, value_enum, env = "TREKKIE_OUTSIDE_REGION", default_value_t = ValidationMode::Flag)]
+    pub outside_region: ValidationMode,
+
+    /// minutes between two aggregations of the reporting point positions, 0 disables them
+    #[arg(long, default_value_t = 60)]
+    pub aggregation_interval: u64,
+
+    /// minutes without new points after which an unfinished run is finished, or deleted if it
+    /// never received a point, 0 disables it
+    #[arg(long, env = "TREKKIE_IDLE_RUN_TIMEOUT", default_value_t = 120)]
+    pub idle_run_timeout: i64,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -0,0 +1,363 @@
+use crate::config::Geofence;
+use crate::structs::Args;
+
+use tlms::locations::gps::InsertGpsPoint;
+
+use chrono::{Duration, NaiveDateTime};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What happens to points which fail the validation
+#[derive(Serialize, Deserialize, ToSchema, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    /// invalid points are not stored
+    Reject,
+    /// invalid points are stored and only reported
+    Flag,
+}
+
+/// Outcome of the validation of one point
+#[derive(Debug, PartialEq)]
+pub enum Validation {
+    Valid,
+    /// the point is stored although it has problems
+    Flagged(Vec<String>),
+    /// the point is not stored
+    Rejected(Vec<String>),
+}
+
+/// Thresholds every submitted gps point is checked against
+#[derive(Debug, Clone)]
+pub struct ValidationConfig {
+    pub mode: ValidationMode,
+    /// handling of points outside of the geofence of the run's region
+    pub outside_region: ValidationMode,
+    /// how far a timestamp may lie in the future to tolerate clock drift
+    pub max_future: Duration,
+    /// how far a timestamp may lie in the past, unlimited for recorded tracks
+    pub max_age: Option<Duration>,
+    /// largest accepted horizontal accuracy in meters
+    pub max_accuracy: f64,
+    /// largest accepted speed in meters per second
+    pub max_speed: f64,
+}
+
+impl ValidationConfig {
+    pub fn from_args(args: &Args) -> ValidationConfig {
+        ValidationConfig {
+            mode: args.invalid_points,
+            outside_region: args.outside_region,
+            max_future: Duration::seconds(args.max_future_seconds),
+            max_age: Some(Duration::days(args.max_point_age_days)),
+            max_accuracy: args.max_accuracy,
+            max_speed: args.max_speed,
+        }
+    }
+
+    /// thresholds for recorded tracks from file uploads and imports, which may be backfilled long
+    /// after they were recorded and therefore have no age limit
+    pub fn without_age_limit(&self) -> ValidationConfig {
+        ValidationConfig {
+            max_age: None,
+            ..self.clone()
+        }
+    }
+
+    /// Checks the point against the thresholds and the geofence of the run's region. Points
+    /// with unusable coordinates are rejected in every mode, positions outside of the region are
+    /// handled according to their own mode and all other problems according to the configured
+    /// mode.
+    pub fn check(
+        &self,
+        point: &InsertGpsPoint,
+        geofence: Option<&Geofence>,
+        now: NaiveDateTime,
+    ) -> Validation {
+        let mut problems = Vec::new();
+
+        let coordinates_valid = point.lat.is_finite()
+            && point.lon.is_finite()
+            && (-90.0..=90.0).contains(&point.lat)
+            && (-180.0..=180.0).contains(&point.lon);
+        if !coordinates_valid {
+            problems.push(format!(
+                "coordinates {}, {} are outside of wgs84",
+                point.lat, point.lon
+            ));
+        }
+
+        if point.timestamp > now + self.max_future {
+            problems.push(format!("timestamp {} lies in the future", point.timestamp));
+        }
+        if let Some(max_age) = self.max_age {
+            if point.timestamp < now - max_age {
+                problems.push(format!(
+                    "timestamp {} is older than {} days",
+                    point.timestamp,
+                    max_age.num_days()
+                ));
+            }
+        }
+
+        if point
+            .elevation
+            .is_some_and(|elevation| !elevation.is_finite())
+        {
+            problems.push("elevation is not a number".to_string());
+        }
+        if let Some(accuracy) = point.accuracy {
+            if !(0.0..=self.max_accuracy).contains(&accuracy) {
+                problems.push(format!(
+                    "accuracy {} is outside of 0 to {} meters",
+                    accuracy, self.max_accuracy
+                ));
+            }
+        }
+        if let Some(accuracy) = point.vertical_accuracy {
+            if !(accuracy.is_finite() && accuracy >= 0.0) {
+                problems.push(format!(
+                    "vertical accuracy {} is negative or not a number",
+                    accuracy
+                ));
+            }
+        }
+        if let Some(speed) = point.speed {
+            if !(0.0..=self.max_speed).contains(&speed) {
+                problems.push(format!(
+                    "speed {} is outside of 0 to {} m/s",
+                    speed, self.max_speed
+                ));
+            }
+        }
+        if let Some(bearing) = point.bearing {
+            if !(0.0..=360.0).contains(&bearing) {
+                problems.push(format!(
+                    "bearing {} is outside of 0 to 360 degrees",
+                    bearing
+                ));
+            }
+        }
+
+        let rejected =
+            !coordinates_valid || (!problems.is_empty() && self.mode == ValidationMode::Reject);
+
+        let outside = coordinates_valid
+            && geofence.is_some_and(|geofence| !geofence.contains(point.lat, point.lon));
+        if outside {
+            problems.push("position lies outside of the region".to_string());
+        }
+        let rejected = rejected || (outside && self.outside_region == ValidationMode::Reject);
+
+        match (problems.is_empty(), rejected) {
+            (true, _) => Validation::Valid,
+            (false, false) => Validation::Flagged(problems),
+            (false, true) => Validation::Rejected(problems),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Coordinate;
+    use crate::formats::tests::time;
+
+    use uuid::Uuid;
+
+    fn config(mode: ValidationMode) -> ValidationConfig {
+        ValidationConfig {
+            mode,
+            outside_region: ValidationMode::Flag,
+            max_future: Duration::seconds(300),
+            max_age: Some(Duration::days(365)),
+            max_accuracy: 100.0,
+            max_speed: 70.0,
+        }
+    }
+
+    fn point(lat: f64, lon: f64) -> InsertGpsPoint {
+        InsertGpsPoint {
+            id: None,
+            trekkie_run: Uuid::nil(),
+            timestamp: time(12, 0, 0),
+            lat,
+            lon,
+            elevation: Some(112.0),
+            accuracy: Some(5.0),
+            vertical_accuracy: Some(3.0),
+            bearing: Some(90.0),
+            speed: Some(10.0),
+        }
+    }
+
+    fn problems(problem: &str) -> Vec<String> {
+        vec![problem.to_string()]
+    }
+
+    #[test]
+    fn valid_point() {
+        let now = time(12, 0, 0);
+        let reject = config(ValidationMode::Reject);
+
+        assert_eq!(
+            reject.check(&point(51.05, 13.74), None, now),
+            Validation::Valid
+        );
+        // limits are inclusive
+        let edge = InsertGpsPoint {
+            timestamp: time(12, 5, 0),
+            accuracy: Some(100.0),
+            speed: Some(70.0),
+            bearing: Some(360.0),
+            ..point(90.0, -180.0)
+        };
+        assert_eq!(reject.check(&edge, None, now), Validation::Valid);
+        // everything besides the position is optional
+        let bare = InsertGpsPoint {
+            elevation: None,
+            accuracy: None,
+            vertical_accuracy: None,
+            bearing: None,
+            speed: None,
+            ..point(51.05, 13.74)
+        };
+        assert_eq!(reject.check(&bare, None, now), Validation::Valid);
+    }
+
+    #[test]
+    fn mode() {
+        let now = time(12, 0, 0);
+        let fast = InsertGpsPoint {
+            speed: Some(80.0),
+            ..point(51.05, 13.74)
+        };
+
+        assert_eq!(
+            config(ValidationMode::Reject).check(&fast, None, now),
+            Validation::Rejected(problems("speed 80 is outside of 0 to 70 m/s"))
+        );
+        assert_eq!(
+            config(ValidationMode::Flag).check(&fast, None, now),
+            Validation::Flagged(problems("speed 80 is outside of 0 to 70 m/s"))
+        );
+    }
+
+    #[test]
+    fn invalid_coordinates() {
+        let now = time(12, 0, 0);
+        let flag = config(ValidationMode::Flag);
+
+        // unusable positions are rejected in every mode
+        assert_eq!(
+            flag.check(&point(91.0, 13.74), None, now),
+            Validation::Rejected(problems("coordinates 91, 13.74 are outside of wgs84"))
+        );
+        assert!(matches!(
+            flag.check(&point(f64::NAN, 13.74), None, now),
+            Validation::Rejected(_)
+        ));
+        assert!(matches!(
+            flag.check(&point(51.05, f64::INFINITY), None, now),
+            Validation::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn timestamps() {
+        let now = time(12, 0, 0);
+        let reject = config(ValidationMode::Reject);
+
+        let future = InsertGpsPoint {
+            timestamp: time(12, 5, 1),
+            ..point(51.05, 13.74)
+        };
+        assert_eq!(
+            reject.check(&future, None, now),
+            Validation::Rejected(problems("timestamp 2024-05-01 12:05:01 lies in the future"))
+        );
+
+        let old = InsertGpsPoint {
+            timestamp: now - Duration::days(400),
+            ..point(51.05, 13.74)
+        };
+        assert_eq!(
+            reject.check(&old, None, now),
+            Validation::Rejected(problems(
+                "timestamp 2023-03-28 12:00:00 is older than 365 days"
+            ))
+        );
+        assert_eq!(
+            reject.without_age_limit().check(&old, None, now),
+            Validation::Valid
+        );
+    }
+
+    #[test]
+    fn several_problems() {
+        let now = time(12, 0, 0);
+        let broken = InsertGpsPoint {
+            elevation: Some(f64::NAN),
+            accuracy: Some(-1.0),
+            vertical_accuracy: Some(f64::NAN),
+            bearing: Some(361.0),
+            speed: Some(-1.0),
+            ..point(51.05, 13.74)
+        };
+
+        assert_eq!(
+            config(ValidationMode::Flag).check(&broken, None, now),
+            Validation::Flagged(vec![
+                "elevation is not a number".to_string(),
+                "accuracy -1 is outside of 0 to 100 meters".to_string(),
+                "vertical accuracy NaN is negative or not a number".to_string(),
+                "speed -1 is outside of 0 to 70 m/s".to_string(),
+                "bearing 361 is outside of 0 to 360 degrees".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn geofence() {
+        let now = time(12, 0, 0);
+        let geofence = Geofence::Polygon(vec![
+            Coordinate {
+                lat: 51.0,
+                lon: 13.6,
+            },
+            Coordinate {
+                lat: 51.0,
+                lon: 13.9,
+            },
+            Coordinate {
+                lat: 51.2,
+                lon: 13.9,
+            },
+            Coordinate {
+                lat: 51.2,
+                lon: 13.6,
+            },
+        ]);
+        let outside = point(52.5, 13.4);
+
+        assert_eq!(
+            config(ValidationMode::Reject).check(&point(51.05, 13.74), Some(&geofence), now),
+            Validation::Valid
+        );
+        // positions outside of the region follow their own mode
+        assert_eq!(
+            config(ValidationMode::Reject).check(&outside, Some(&geofence), now),
+            Validation::Flagged(problems("position lies outside of the region"))
+        );
+        let strict = ValidationConfig {
+            outside_region: ValidationMode::Reject,
+            ..config(ValidationMode::Flag)
+        };
+        assert_eq!(
+            strict.check(&outside, Some(&geofence), now),
+            Validation::Rejected(problems("position lies outside of the region"))
+        );
+        assert_eq!(strict.check(&outside, None, now), Validation::Valid);
+    }
+}